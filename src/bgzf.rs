@@ -0,0 +1,368 @@
+// Block-compressed files compatible with BGZF: a series of independent gzip members.
+// Position in such file is a virtual offset `block_start << 16 | offset_in_block`,
+// so any record can be read without decompressing everything before it.
+use std::io::prelude::*;
+use std::io::{self, SeekFrom};
+use byteorder::{LE, ReadBytesExt, WriteBytesExt};
+use flate2::{Compression, Crc};
+use crate::shared::Fork;
+
+const HEADER_LEN: usize = 18;
+const FOOTER_LEN: usize = 8;
+/// Uncompressed size of a block. Compressed block must fit into 64 KiB even if data does not compress
+const BLOCK_DATA: usize = 0xff00;
+/// Empty block marking the end of file
+const EOF_BLOCK: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Gzip header with `BC` extra field
+pub(crate) fn is_header(header: &[u8]) -> bool {
+    header.len() >= 16 && header[..4] == [0x1f, 0x8b, 0x08, 0x04] && header[12..16] == [b'B', b'C', 0x02, 0x00]
+}
+
+pub struct BgzfWriter<W: Write> {
+    inner: Option<W>,
+    buf: Vec<u8>,
+    level: Compression,
+    /// Compressed bytes written
+    written: u64,
+}
+
+impl<W: Write> BgzfWriter<W> {
+    pub fn new(inner: W, level: Compression) -> Self {
+        BgzfWriter {
+            inner: Some(inner),
+            buf: Vec::with_capacity(BLOCK_DATA),
+            level,
+            written: 0
+        }
+    }
+
+    /// Position of the next written byte
+    pub fn virtual_offset(&self) -> u64 {
+        self.written << 16 | self.buf.len() as u64
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), self.level);
+        encoder.write_all(&self.buf)?;
+        let compressed = encoder.finish()?;
+        let mut crc = Crc::new();
+        crc.update(&self.buf);
+
+        let size = HEADER_LEN + compressed.len() + FOOTER_LEN;
+        let inner = self.inner.as_mut().unwrap();
+        inner.write_all(&[0x1f, 0x8b, 0x08, 0x04, 0, 0, 0, 0, 0, 0xff, 0x06, 0x00, b'B', b'C', 0x02, 0x00])?;
+        inner.write_u16::<LE>((size - 1) as u16)?;
+        inner.write_all(&compressed)?;
+        inner.write_u32::<LE>(crc.sum())?;
+        inner.write_u32::<LE>(self.buf.len() as u32)?;
+        self.written += size as u64;
+        self.buf.clear();
+        Ok(())
+    }
+
+    fn try_finish(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            self.write_block()?;
+        }
+        let inner = self.inner.as_mut().unwrap();
+        inner.write_all(&EOF_BLOCK)?;
+        self.written += EOF_BLOCK.len() as u64;
+        inner.flush()
+    }
+
+    /// Writes the rest of data and end of file marker
+    pub fn finish(mut self) -> io::Result<W> {
+        self.try_finish()?;
+        Ok(self.inner.take().unwrap())
+    }
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let num = buf.len().min(BLOCK_DATA - self.buf.len());
+        self.buf.extend_from_slice(&buf[..num]);
+        if self.buf.len() == BLOCK_DATA {
+            self.write_block()?;
+        }
+        Ok(num)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            self.write_block()?;
+        }
+        self.inner.as_mut().unwrap().flush()
+    }
+}
+
+impl<W: Write> Drop for BgzfWriter<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = self.try_finish();
+        }
+    }
+}
+
+/// Has its own buffer, so it must not be wrapped into BufReader: position of BufReader is not a virtual offset
+pub struct BgzfReader<R> {
+    inner: R,
+    /// Decompressed current block
+    block: Vec<u8>,
+    block_start: u64,
+    next_block: u64,
+    pos: usize,
+    /// Nothing is loaded yet
+    empty: bool,
+    eof: bool,
+}
+
+impl<R: Read + Seek> BgzfReader<R> {
+    pub fn new(inner: R) -> Self {
+        BgzfReader {
+            inner,
+            block: Vec::new(),
+            block_start: 0,
+            next_block: 0,
+            pos: 0,
+            empty: true,
+            eof: false
+        }
+    }
+
+    fn load_block(&mut self, start: u64) -> io::Result<()> {
+        self.inner.seek(SeekFrom::Start(start))?;
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        self.inner.by_ref().take(HEADER_LEN as u64).read_to_end(&mut header)?;
+        self.block.clear();
+        self.block_start = start;
+        self.next_block = start;
+        self.pos = 0;
+        self.empty = false;
+        if header.is_empty() {
+            self.eof = true;
+            return Ok(());
+        }
+        if header.len() < HEADER_LEN || !is_header(&header) {
+            return Err(invalid("Invalid header of compressed block"));
+        }
+        let size = (&header[16..]).read_u16::<LE>()? as usize + 1;
+        if size < HEADER_LEN + FOOTER_LEN {
+            return Err(invalid("Invalid size of compressed block"));
+        }
+        let mut rest = vec![0; size - HEADER_LEN];
+        self.inner.read_exact(&mut rest)?;
+        let (compressed, mut footer) = rest.split_at(rest.len() - FOOTER_LEN);
+        let crc = footer.read_u32::<LE>()?;
+        let len = footer.read_u32::<LE>()? as usize;
+
+        self.block.reserve(len);
+        flate2::read::DeflateDecoder::new(compressed).read_to_end(&mut self.block)?;
+        let mut actual = Crc::new();
+        actual.update(&self.block);
+        if self.block.len() != len || actual.sum() != crc {
+            return Err(invalid("Compressed block is corrupted"));
+        }
+        self.next_block = start + size as u64;
+        self.eof = false;
+        Ok(())
+    }
+
+    /// Current virtual offset. End of block is reported as start of the next one
+    pub fn virtual_offset(&self) -> u64 {
+        if self.pos >= self.block.len() && !self.empty {
+            self.next_block << 16
+        } else {
+            self.block_start << 16 | self.pos as u64
+        }
+    }
+}
+
+impl<R: Read + Seek> Read for BgzfReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos >= self.block.len() {
+            if self.eof {
+                return Ok(0);
+            }
+            // Empty blocks are skipped
+            self.load_block(self.next_block)?;
+        }
+        let num = buf.len().min(self.block.len() - self.pos);
+        buf[..num].copy_from_slice(&self.block[self.pos..self.pos + num]);
+        self.pos += num;
+        Ok(num)
+    }
+}
+
+impl<R: Read + Seek> Seek for BgzfReader<R> {
+    /// Only virtual offsets from `SeekFrom::Start`, current position and the end are supported
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match pos {
+            SeekFrom::Start(x) => {
+                let (block, offset) = (x >> 16, (x & 0xffff) as usize);
+                if self.empty || block != self.block_start {
+                    self.load_block(block)?;
+                }
+                if offset > self.block.len() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "Offset is outside of block"));
+                }
+                self.pos = offset;
+                Ok(x)
+            },
+            SeekFrom::Current(0) => Ok(self.virtual_offset()),
+            SeekFrom::End(0) => {
+                let len = self.inner.seek(SeekFrom::End(0))?;
+                self.block.clear();
+                self.block_start = len;
+                self.next_block = len;
+                self.pos = 0;
+                self.empty = false;
+                self.eof = true;
+                Ok(len << 16)
+            },
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "Relative seeks are not supported in compressed data"))
+        }
+    }
+}
+
+impl<R: Fork> Fork for BgzfReader<R> {
+    /// Every block is loaded with explicit seek, so position of the inner reader does not matter
+    fn fork(&self) -> io::Result<Self> {
+        Ok(BgzfReader {
+            inner: self.inner.fork()?,
+            block: self.block.clone(),
+            block_start: self.block_start,
+            next_block: self.next_block,
+            pos: self.pos,
+            empty: self.empty,
+            eof: self.eof
+        })
+    }
+
+    fn is_block_compressed(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use chrono::NaiveDateTime;
+    use crate::data::{read_chunk, resync, write_chunk, UserInfo};
+
+    fn user(pikabu_id: i64) -> UserInfo {
+        UserInfo {
+            name: format!("user{}", pikabu_id),
+            pikabu_id,
+            seek: None,
+            // 16 KB, so a block holds about four records
+            comments: (0..2000).map(|x| NaiveDateTime::from_timestamp(1_500_000_000 + pikabu_id * 10_000 + x, 0)).collect(),
+            aliases: Vec::new(),
+            streams: Vec::new(),
+            meta: Vec::new(),
+        }
+    }
+
+    /// Compressed data and virtual offsets of records, the last one is offset of terminating record
+    fn records(count: i64) -> (Vec<u8>, Vec<u64>) {
+        let mut writer = BgzfWriter::new(Vec::new(), Compression::fast());
+        let mut offsets = Vec::new();
+        for id in 0..count {
+            offsets.push(writer.virtual_offset());
+            write_chunk(&mut writer, Some(&user(id))).unwrap();
+        }
+        offsets.push(writer.virtual_offset());
+        write_chunk(&mut writer, None).unwrap();
+        (writer.finish().unwrap(), offsets)
+    }
+
+    /// Index of a record which starts in one block and ends in another
+    fn crossing(offsets: &[u64]) -> usize {
+        offsets.windows(2)
+            .position(|x| x[0] >> 16 != x[1] >> 16 && x[1] & 0xffff != 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let data: Vec<u8> = (0..3 * BLOCK_DATA + 100).map(|x| (x * 7 % 251) as u8).collect();
+        let mut writer = BgzfWriter::new(Vec::new(), Compression::default());
+        // Chunks don't match blocks
+        for chunk in data.chunks(1000) {
+            writer.write_all(chunk).unwrap();
+        }
+        let compressed = writer.finish().unwrap();
+        assert!(is_header(&compressed));
+
+        let mut read = Vec::new();
+        BgzfReader::new(Cursor::new(&compressed)).read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+        // Also readable by plain gzip decoder
+        let mut read = Vec::new();
+        flate2::read::MultiGzDecoder::new(&compressed[..]).read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+    }
+
+    #[test]
+    fn seek_to_written_offset() {
+        let (compressed, offsets) = records(20);
+        let mut reader = BgzfReader::new(Cursor::new(&compressed));
+        // Backwards, so every seek loads a block again
+        for (id, offset) in offsets[..20].iter().enumerate().rev() {
+            assert_eq!(reader.seek(SeekFrom::Start(*offset)).unwrap(), *offset);
+            let found = read_chunk(&mut reader, None).unwrap().unwrap();
+            assert_eq!(found.pikabu_id, id as i64);
+            assert_eq!(reader.virtual_offset(), offsets[id + 1]);
+        }
+        reader.seek(SeekFrom::Start(offsets[20])).unwrap();
+        assert!(read_chunk(&mut reader, None).unwrap().is_none());
+    }
+
+    #[test]
+    fn eof_block() {
+        let empty = BgzfWriter::new(Vec::new(), Compression::default()).finish().unwrap();
+        assert_eq!(empty, EOF_BLOCK);
+        let mut read = Vec::new();
+        BgzfReader::new(Cursor::new(&empty)).read_to_end(&mut read).unwrap();
+        assert!(read.is_empty());
+
+        let (compressed, _) = records(3);
+        assert!(compressed.ends_with(&EOF_BLOCK));
+        let mut reader = BgzfReader::new(Cursor::new(&compressed));
+        let end = reader.seek(SeekFrom::End(0)).unwrap();
+        assert_eq!(end, (compressed.len() as u64) << 16);
+        assert_eq!(reader.read(&mut [0; 16]).unwrap(), 0);
+    }
+
+    #[test]
+    fn record_across_blocks() {
+        let (compressed, offsets) = records(20);
+        let idx = crossing(&offsets);
+        let mut reader = BgzfReader::new(Cursor::new(&compressed));
+        reader.seek(SeekFrom::Start(offsets[idx])).unwrap();
+        let found = read_chunk(&mut reader, Some(offsets[idx] as usize)).unwrap().unwrap();
+        assert_eq!(found.pikabu_id, idx as i64);
+        assert_eq!(found.comments, user(idx as i64).comments);
+        assert_eq!(reader.virtual_offset(), offsets[idx + 1]);
+    }
+
+    #[test]
+    fn resync_across_blocks() {
+        let (compressed, offsets) = records(20);
+        let idx = crossing(&offsets);
+        let mut reader = BgzfReader::new(Cursor::new(&compressed));
+        // Starting inside the record, its end is in the next block
+        assert_eq!(resync(&mut reader, offsets[idx] + 1).unwrap(), Some(offsets[idx + 1]));
+        // Terminating record is found too, but nothing after it
+        assert_eq!(resync(&mut reader, offsets[19] + 1).unwrap(), Some(offsets[20]));
+        assert_eq!(resync(&mut reader, offsets[20] + 1).unwrap(), None);
+    }
+}
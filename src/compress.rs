@@ -0,0 +1,271 @@
+// Compression of data and json. Codec of input is detected by its first bytes
+use std::io::prelude::*;
+use std::io::{self, SeekFrom};
+use flate2::Compression;
+use byteorder::{ByteOrder, LE};
+use crate::bgzf::{self, BgzfWriter};
+use crate::Res;
+
+/// Enough to recognize every codec
+const MAGIC_LEN: usize = 18;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    #[default]
+    None,
+    Gzip,
+    /// Block-compressed gzip, can be read with seeks
+    Bgzf,
+    Zstd,
+    Xz,
+}
+
+impl std::str::FromStr for Codec {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Res<Self> {
+        Ok(match s {
+            "none" => Codec::None,
+            "gzip" | "gz" => Codec::Gzip,
+            "bgzf" => Codec::Bgzf,
+            "zstd" | "zst" => Codec::Zstd,
+            "xz" => Codec::Xz,
+            _ => return Err(format_err!("Unknown codec: {}. Use none, gzip, bgzf, zstd or xz", s))
+        })
+    }
+}
+
+/// Plain data starts with id of the first user, so it may look like magic of a codec.
+/// Gzip magic is only two bytes: any id equal to 35615 modulo 65536 has it, and e.g. id 559903
+/// starts with `1f 8b 08 00`. So fields of gzip header are checked too: compression method
+/// is deflate, reserved flags are zero and XFL has one of defined values. In plain data XFL is
+/// the first byte of name, which is never a control character. Magic of other codecs is too
+/// long to be a real id
+fn is_gzip(header: &[u8]) -> bool {
+    header.len() >= 10 && header[..3] == [0x1f, 0x8b, 0x08] && header[3] & 0xe0 == 0 && [0, 2, 4].contains(&header[8])
+        && !is_empty_name(header)
+}
+
+/// XFL is zero in plain data when name is empty. Then a timestamp or a tag follows, so high bytes
+/// are zero or those of a tag. Deflate of real data is very unlikely to start like that
+fn is_empty_name(header: &[u8]) -> bool {
+    if header.len() < 17 || header[8] != 0 {
+        return false;
+    }
+    let next = LE::read_i64(&header[9..17]);
+    next <= crate::data::MAX_TAG || (0..1 << 40).contains(&next)
+}
+
+impl Codec {
+    pub fn detect(header: &[u8]) -> Codec {
+        if bgzf::is_header(header) {
+            Codec::Bgzf
+        } else if is_gzip(header) {
+            Codec::Gzip
+        } else if header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Codec::Zstd
+        } else if header.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Codec::Xz
+        } else {
+            Codec::None
+        }
+    }
+
+    /// Checks first bytes of data, reader is moved back to the start
+    pub fn detect_seek<R: Read + Seek>(reader: &mut R) -> io::Result<Codec> {
+        reader.seek(SeekFrom::Start(0))?;
+        let mut header = Vec::with_capacity(MAGIC_LEN);
+        reader.by_ref().take(MAGIC_LEN as u64).read_to_end(&mut header)?;
+        reader.seek(SeekFrom::Start(0))?;
+        Ok(Codec::detect(&header))
+    }
+
+    /// Data can be read with seeks without decompressing everything
+    pub fn is_seekable(self) -> bool {
+        self == Codec::None || self == Codec::Bgzf
+    }
+
+    /// Checks level, or returns the default one if it is None
+    pub fn level(self, level: Option<u32>) -> Res<u32> {
+        let max = self.max_level();
+        match level {
+            None => Ok(self.default_level()),
+            Some(x) if x <= max => Ok(x),
+            Some(_) => Err(format_err!("Level of {:?} must be at most {}", self, max))
+        }
+    }
+
+    fn default_level(self) -> u32 {
+        match self {
+            Codec::None => 0,
+            Codec::Gzip | Codec::Bgzf => 3,
+            Codec::Zstd => 3,
+            Codec::Xz => 6,
+        }
+    }
+
+    fn max_level(self) -> u32 {
+        match self {
+            Codec::None => 0,
+            Codec::Gzip | Codec::Bgzf | Codec::Xz => 9,
+            Codec::Zstd => 21,
+        }
+    }
+}
+
+/// Decompresses data of any codec. Bytes read while detecting are put back in front of the rest
+pub fn decoder<'a, R: BufRead + Send + 'a>(mut reader: R) -> io::Result<Box<dyn Read + Send + 'a>> {
+    // Pipes may return less than asked, so header is read until it is complete or the stream ends
+    let mut header = Vec::with_capacity(MAGIC_LEN);
+    reader.by_ref().take(MAGIC_LEN as u64).read_to_end(&mut header)?;
+    let codec = Codec::detect(&header);
+    let reader = io::Cursor::new(header).chain(reader);
+    Ok(match codec {
+        Codec::None => Box::new(reader),
+        // Block-compressed data is a series of gzip members
+        Codec::Gzip | Codec::Bgzf => Box::new(flate2::bufread::MultiGzDecoder::new(reader)),
+        Codec::Zstd => Box::new(zstd::stream::read::Decoder::with_buffer(reader)?),
+        Codec::Xz => Box::new(xz2::bufread::XzDecoder::new_multi_decoder(reader)),
+    })
+}
+
+pub enum Encoder<W: Write> {
+    None(W),
+    Gzip(flate2::write::GzEncoder<W>),
+    Bgzf(BgzfWriter<W>),
+    Zstd(zstd::stream::write::Encoder<W>),
+    Xz(xz2::write::XzEncoder<W>),
+}
+
+impl<W: Write> Encoder<W> {
+    /// Level must be checked with `Codec::level`
+    pub fn new(writer: W, codec: Codec, level: u32) -> io::Result<Self> {
+        Ok(match codec {
+            Codec::None => Encoder::None(writer),
+            Codec::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(writer, Compression::new(level))),
+            Codec::Bgzf => Encoder::Bgzf(BgzfWriter::new(writer, Compression::new(level))),
+            Codec::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(writer, level as i32)?),
+            Codec::Xz => Encoder::Xz(xz2::write::XzEncoder::new(writer, level)),
+        })
+    }
+
+    /// Must be called, otherwise end of stream may be lost
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::None(mut x) => x.flush().map(|_| x),
+            Encoder::Gzip(x) => x.finish(),
+            Encoder::Bgzf(x) => x.finish(),
+            Encoder::Zstd(x) => x.finish(),
+            Encoder::Xz(x) => x.finish(),
+        }
+    }
+
+    fn inner(&mut self) -> &mut dyn Write {
+        match self {
+            Encoder::None(x) => x,
+            Encoder::Gzip(x) => x,
+            Encoder::Bgzf(x) => x,
+            Encoder::Zstd(x) => x,
+            Encoder::Xz(x) => x,
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDateTime;
+    use crate::data::{write_chunk, UserInfo};
+
+    fn compressed(codec: Codec) -> Vec<u8> {
+        let mut encoder = Encoder::new(Vec::new(), codec, codec.level(None).unwrap()).unwrap();
+        encoder.write_all(b"some data").unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn detects_codecs() {
+        for codec in &[Codec::Gzip, Codec::Bgzf, Codec::Zstd, Codec::Xz] {
+            assert_eq!(Codec::detect(&compressed(*codec)), *codec);
+        }
+        // XFL is zero for most levels
+        for level in 0..=9 {
+            let mut encoder = Encoder::new(Vec::new(), Codec::Gzip, level).unwrap();
+            for i in 0..100 {
+                writeln!(encoder, r#"{{"created_at_timestamp": {}, "author_id": {}, "author_username": "user{}"}}"#, 1_500_000_000 + i * 97, i % 7, i % 7).unwrap();
+            }
+            assert_eq!(Codec::detect(&encoder.finish().unwrap()), Codec::Gzip, "level {}", level);
+        }
+    }
+
+    /// Pipe which gives a byte at a time
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let num = buf.len().min(self.0.len()).min(1);
+            buf[..num].copy_from_slice(&self.0[..num]);
+            self.0 = &self.0[num..];
+            Ok(num)
+        }
+    }
+
+    #[test]
+    fn detects_codecs_of_short_reads() {
+        for codec in &[Codec::None, Codec::Gzip, Codec::Bgzf, Codec::Zstd, Codec::Xz] {
+            let data = compressed(*codec);
+            let mut decoded = Vec::new();
+            decoder(io::BufReader::new(Trickle(&data))).unwrap().read_to_end(&mut decoded).unwrap();
+            assert_eq!(decoded, b"some data", "{:?}", codec);
+        }
+    }
+
+    #[test]
+    fn plain_data_with_gzip_header() {
+        // Id is a gzip header, empty name is XFL and then comes OS
+        let id = i64::from_le_bytes([0x1f, 0x8b, 0x08, 0, 0x5e, 0x8b, 0x43, 0x5d]);
+        for comments in &[0, 1] {
+            let user = UserInfo {
+                name: String::new(),
+                pikabu_id: id,
+                seek: None,
+                comments: (0..*comments).map(|x| NaiveDateTime::from_timestamp(1_500_000_000 + x, 0)).collect(),
+                aliases: Vec::new(),
+                streams: Vec::new(),
+                meta: Vec::new(),
+            };
+            let mut data = Vec::new();
+            write_chunk(&mut data, Some(&user)).unwrap();
+            assert_eq!(Codec::detect(&data), Codec::None);
+        }
+    }
+
+    #[test]
+    fn plain_data_with_gzip_magic() {
+        for id in &[35615, 559_903, 0x0108_8b1f] {
+            let user = UserInfo {
+                name: "user".to_string(),
+                pikabu_id: *id,
+                seek: None,
+                comments: vec![NaiveDateTime::from_timestamp(1_500_000_000, 0)],
+                aliases: Vec::new(),
+                streams: Vec::new(),
+                meta: Vec::new(),
+            };
+            let mut data = Vec::new();
+            write_chunk(&mut data, Some(&user)).unwrap();
+            assert_eq!(&data[..2], &[0x1f, 0x8b]);
+            assert_eq!(Codec::detect(&data), Codec::None);
+        }
+    }
+}
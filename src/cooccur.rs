@@ -0,0 +1,189 @@
+use chrono::{NaiveDateTime, Duration};
+use streaming_iterator::StreamingIterator;
+use std::collections::{HashMap, HashSet};
+use crate::data::*;
+use crate::Res;
+
+/// Events of every kind which have a story, as `(story, time)`
+fn story_events(user: &UserInfo) -> impl Iterator<Item=(i64, NaiveDateTime)> + '_ {
+    user.kinds().flat_map(move |kind| {
+        user.events(kind).iter()
+            .zip(user.meta(kind))
+            .filter_map(|(ts, meta)| meta.story_id.map(|x| (x, *ts)))
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct Cooccurrence {
+    pub pikabu_id: i64,
+    pub name: String,
+    /// Events of the user close to events of target in the same story
+    pub count: usize,
+    /// Stories with at least one such event
+    pub stories: usize,
+    /// Events of the user which have a story
+    pub events: usize,
+    /// How much more often the user is close to target than an average user
+    pub lift: f64,
+}
+
+/// Share of close events of the user over the share of an average user. 0 when either has no events,
+/// so results never contain NaN or infinity
+fn lift(count: usize, events: usize, total_count: usize, total_events: usize) -> f64 {
+    if events == 0 || total_count == 0 {
+        return 0.0;
+    }
+    (count as f64 / events as f64) / (total_count as f64 / total_events as f64)
+}
+
+pub struct CooccurSettings {
+    pub top: usize,
+    /// Events further from each other are unrelated
+    pub window: Duration,
+    /// Users with less events close to target are skipped
+    pub min_count: usize,
+}
+
+/// Finds users who write in the same stories at the same time as `target`. Target users are treated as one.
+/// Only events with `story_id` are used
+pub fn find_cooccur<D: SimpleData>(data: &mut D, target: &[UserInfo], settings: CooccurSettings) -> Res<Vec<Cooccurrence>> {
+    let mut stories: HashMap<i64, Vec<NaiveDateTime>> = HashMap::new();
+    for (story, ts) in target.iter().flat_map(story_events) {
+        stories.entry(story).or_default().push(ts);
+    }
+    if stories.is_empty() {
+        return Err(format_err!("Target has no events with stories"));
+    }
+    for times in stories.values_mut() {
+        times.sort();
+    }
+    let ids: HashSet<i64> = target.iter().map(|x| x.pikabu_id).collect();
+
+    let is_close = |story: i64, ts: NaiveDateTime| {
+        let times = match stories.get(&story) {
+            Some(x) => x,
+            None => return false
+        };
+        // First event of target which is not too early
+        let idx = match times.binary_search(&(ts - settings.window)) {
+            Ok(x) | Err(x) => x
+        };
+        times.get(idx).map_or(false, |x| *x <= ts + settings.window)
+    };
+
+    let (mut total_count, mut total_events) = (0, 0);
+    let mut found = Vec::new();
+    let mut reader = data.get_reader(ReadConfig::None)?;
+    while let Some(user) = reader.next() {
+        if ids.contains(&user.pikabu_id) {
+            continue;
+        }
+        let (mut count, mut events) = (0, 0);
+        let mut close_stories = HashSet::new();
+        for (story, ts) in story_events(user) {
+            events += 1;
+            if is_close(story, ts) {
+                count += 1;
+                close_stories.insert(story);
+            }
+        }
+        total_count += count;
+        total_events += events;
+        if count == 0 || count < settings.min_count {
+            continue;
+        }
+        found.push(Cooccurrence {
+            pikabu_id: user.pikabu_id,
+            name: user.name.clone(),
+            count,
+            stories: close_stories.len(),
+            events,
+            lift: 0.0
+        });
+    }
+    reader.finish()?;
+
+    for i in &mut found {
+        i.lift = lift(i.count, i.events, total_count, total_events);
+    }
+    found.sort_by(|a, b| {
+        b.count.cmp(&a.count)
+            .then(b.lift.partial_cmp(&a.lift).unwrap_or(std::cmp::Ordering::Equal))
+    });
+    found.truncate(settings.top);
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn at(minute: i64) -> NaiveDateTime {
+        NaiveDate::from_ymd(2017, 7, 10).and_hms(0, 0, 0) + Duration::minutes(minute)
+    }
+
+    /// Comments as `(story, minute)`
+    fn user(pikabu_id: i64, comments: &[(i64, i64)]) -> UserInfo {
+        let mut user = UserInfo {
+            name: format!("user{}", pikabu_id),
+            pikabu_id,
+            seek: None,
+            comments: Vec::new(),
+            aliases: Vec::new(),
+            streams: Vec::new(),
+            meta: Vec::new(),
+        };
+        for (story, minute) in comments {
+            user.push_event(COMMENTS, at(*minute), Meta { story_id: Some(*story), ..Meta::default() });
+        }
+        user.sort_events();
+        user
+    }
+
+    fn find(target: &UserInfo, users: &[UserInfo]) -> Res<Vec<Cooccurrence>> {
+        let mut buf = Vec::new();
+        for x in users {
+            write_chunk(&mut buf, Some(x)).unwrap();
+        }
+        write_chunk(&mut buf, None).unwrap();
+        let mut data: Data<_, CacheRef> = Data::new(&buf[..]);
+        let settings = CooccurSettings { top: 10, window: Duration::minutes(10), min_count: 1 };
+        find_cooccur(&mut data, std::slice::from_ref(target), settings)
+    }
+
+    #[test]
+    fn lift_is_never_nan() {
+        assert_eq!(lift(0, 0, 0, 0), 0.0);
+        assert_eq!(lift(0, 5, 0, 10), 0.0);
+        assert_eq!(lift(1, 0, 1, 10), 0.0);
+        assert_eq!(lift(2, 4, 5, 20), 2.0);
+    }
+
+    #[test]
+    fn finds_users_close_in_the_same_story() {
+        let target = user(1, &[(1, 0), (2, 100)]);
+        let users = [
+            target.clone(),
+            // Close twice in both stories and once far away
+            user(2, &[(1, 5), (2, 95), (2, 300)]),
+            // Close time, but other story
+            user(3, &[(3, 0)]),
+            // Same story, but too late, and once close
+            user(4, &[(1, 30), (2, 110)]),
+            user(5, &[]),
+        ];
+        let found = find(&target, &users).unwrap();
+        let ids: Vec<(i64, usize, usize, usize)> = found.iter().map(|x| (x.pikabu_id, x.count, x.stories, x.events)).collect();
+        assert_eq!(ids, vec![(2, 2, 2, 3), (4, 1, 1, 2)]);
+        // 3 close events of 6 from all other users
+        assert!((found[0].lift - 2.0 / 3.0 / 0.5).abs() < 1e-9);
+        assert!(found.iter().all(|x| x.lift.is_finite()));
+    }
+
+    #[test]
+    fn target_needs_stories() {
+        let target = user(1, &[]);
+        assert!(find(&target, &[user(2, &[(1, 0)])]).is_err());
+    }
+}
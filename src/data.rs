@@ -8,17 +8,258 @@ use streaming_iterator::StreamingIterator;
 use std::io::SeekFrom;
 use parking_lot::Mutex;
 use std::borrow::BorrowMut;
+use crate::shared::Fork;
+use serde::Serialize;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-#[derive(Debug, Clone)]
+// Record is `pikabu_id`, `name\0`, comment timestamps, optional tagged sections and `END`.
+// Tags are values close to `i64::MIN`, which are never valid timestamps. Old files without tags
+// are still readable, but files with tags are not readable by versions before tags were added:
+// they take tags for timestamps. Unknown tags are an error, so files of newer versions are rejected too.
+
+/// Terminates list of timestamps and the whole chunk
+const END: i64 = i64::MIN;
+/// Starts name history section
+const TAG_ALIASES: i64 = i64::MIN + 1;
+/// Starts events of another kind: kind, count and timestamps
+const TAG_STREAM: i64 = i64::MIN + 2;
+/// Starts attributes of events of one kind: kind, count and attributes of every event
+const TAG_META: i64 = i64::MIN + 3;
+/// Values up to this one are reserved for tags
+pub(crate) const MAX_TAG: i64 = i64::MIN + 0xffff;
+
+/// Bits telling which attributes of event are stored
+const META_ID: u8 = 1;
+const META_STORY: u8 = 2;
+const META_RATING: u8 = 4;
+
+/// Kind of events stored in `UserInfo::comments`
+pub const COMMENTS: &str = "comments";
+
+static USERS_READ: AtomicU64 = AtomicU64::new(0);
+
+/// Users read sequentially from all data since start. Lookups by offset are not counted
+pub fn users_read() -> u64 {
+    USERS_READ.load(Ordering::Relaxed)
+}
+
+fn count_read(user: Option<UserInfo>) -> Option<UserInfo> {
+    if user.is_some() {
+        USERS_READ.fetch_add(1, Ordering::Relaxed);
+    }
+    user
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Alias {
+    pub name: String,
+    pub first_seen: NaiveDateTime,
+    pub last_seen: NaiveDateTime,
+}
+
+/// Events of one kind, like posts or given upvotes
+#[derive(Debug, Clone, Serialize)]
+pub struct Stream {
+    pub kind: String,
+    /// Sorted
+    pub events: Vec<NaiveDateTime>,
+}
+
+/// Optional attributes of a single event
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Meta {
+    /// Id of the comment or post itself
+    pub id: Option<i64>,
+    pub story_id: Option<i64>,
+    pub rating: Option<i32>,
+}
+
+impl Meta {
+    pub fn is_empty(&self) -> bool {
+        *self == Meta::default()
+    }
+}
+
+/// Attributes of events of one kind, in the same order as events
+#[derive(Debug, Clone, Serialize)]
+pub struct StreamMeta {
+    pub kind: String,
+    pub attrs: Vec<Meta>,
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct UserInfo {
     pub name: String,
     pub pikabu_id: i64,
     pub seek: Option<usize>,
     pub comments: Vec<NaiveDateTime>,
+    /// Every name user was seen with, oldest first. Empty when user was never renamed
+    pub aliases: Vec<Alias>,
+    /// Events of other kinds than comments
+    pub streams: Vec<Stream>,
+    /// Only kinds with at least one event having attributes are here
+    pub meta: Vec<StreamMeta>,
 }
 
-pub fn read_chunk<R: Read>(mut reader: R, seek: Option<usize>) -> Res<Option<UserInfo>> {
-    let pikabu_id = reader.read_i64::<LE>()?;
+impl UserInfo {
+    /// Events of the kind. Empty when user has none
+    pub fn events(&self, kind: &str) -> &[NaiveDateTime] {
+        if kind == COMMENTS {
+            return &self.comments;
+        }
+        self.streams.iter()
+            .find(|x| x.kind == kind)
+            .map_or(&[], |x| &x.events[..])
+    }
+
+    /// Events of the kind, stream is created when it does not exist
+    pub fn events_mut(&mut self, kind: &str) -> &mut Vec<NaiveDateTime> {
+        if kind == COMMENTS {
+            return &mut self.comments;
+        }
+        let idx = match self.streams.iter().position(|x| x.kind == kind) {
+            Some(x) => x,
+            None => {
+                self.streams.push(Stream { kind: kind.to_string(), events: Vec::new() });
+                self.streams.len() - 1
+            }
+        };
+        &mut self.streams[idx].events
+    }
+
+    /// Moves events of the kind out of user
+    pub fn take_events(&mut self, kind: &str) -> Vec<NaiveDateTime> {
+        std::mem::take(self.events_mut(kind))
+    }
+
+    /// Attributes of events of the kind, in the same order. Empty when there are none
+    pub fn meta(&self, kind: &str) -> &[Meta] {
+        self.meta.iter()
+            .find(|x| x.kind == kind)
+            .map_or(&[], |x| &x.attrs[..])
+    }
+
+    /// Adds event to the end, events must be sorted later by `sort_events`
+    pub fn push_event(&mut self, kind: &str, ts: NaiveDateTime, meta: Meta) {
+        let len = self.events(kind).len();
+        let idx = match self.meta.iter().position(|x| x.kind == kind) {
+            Some(x) => Some(x),
+            None if !meta.is_empty() => {
+                self.meta.push(StreamMeta { kind: kind.to_string(), attrs: Vec::new() });
+                Some(self.meta.len() - 1)
+            },
+            None => None
+        };
+        if let Some(idx) = idx {
+            let attrs = &mut self.meta[idx].attrs;
+            attrs.resize(len, Meta::default());
+            attrs.push(meta);
+        }
+        self.events_mut(kind).push(ts);
+    }
+
+    /// Sorts events of every kind together with their attributes
+    pub fn sort_events(&mut self) {
+        for kind in self.stored_kinds() {
+            let mut attrs = match self.meta.iter().position(|x| x.kind == kind) {
+                Some(idx) => std::mem::take(&mut self.meta[idx].attrs),
+                None => {
+                    self.events_mut(&kind).sort();
+                    continue;
+                }
+            };
+            let events = self.events_mut(&kind);
+            attrs.resize(events.len(), Meta::default());
+            let mut pairs: Vec<_> = events.drain(..).zip(attrs).collect();
+            pairs.sort_by_key(|x| x.0);
+            let (sorted, attrs): (Vec<_>, Vec<_>) = pairs.into_iter().unzip();
+            *events = sorted;
+            if let Some(x) = self.meta.iter_mut().find(|x| x.kind == kind) {
+                x.attrs = attrs;
+            }
+        }
+    }
+
+    /// Keeps only matching events, attributes are kept in sync
+    pub fn retain_events<P: Fn(NaiveDateTime, &Meta) -> bool>(&mut self, pred: P) {
+        for kind in self.stored_kinds() {
+            let keep: Vec<bool> = {
+                let attrs = self.meta(&kind);
+                self.events(&kind).iter()
+                    .enumerate()
+                    .map(|(i, x)| pred(*x, &attrs.get(i).cloned().unwrap_or_default()))
+                    .collect()
+            };
+            let mut it = keep.iter();
+            self.events_mut(&kind).retain(|_| *it.next().unwrap());
+            if let Some(x) = self.meta.iter_mut().find(|x| x.kind == kind) {
+                let mut it = keep.iter();
+                x.attrs.retain(|_| it.next().cloned().unwrap_or(false));
+            }
+        }
+    }
+
+    /// Every kind including empty ones
+    fn stored_kinds(&self) -> Vec<String> {
+        std::iter::once(COMMENTS.to_string())
+            .chain(self.streams.iter().map(|x| x.kind.clone()))
+            .collect()
+    }
+
+    /// Kinds of events user has, comments first
+    pub fn kinds(&self) -> impl Iterator<Item=&str> {
+        let comments = if self.comments.is_empty() { None } else { Some(COMMENTS) };
+        comments.into_iter()
+            .chain(self.streams.iter()
+                .filter(|x| !x.events.is_empty())
+                .map(|x| x.kind.as_str()))
+    }
+
+    /// Current name followed by all former names
+    pub fn names(&self) -> impl Iterator<Item=&str> {
+        let current = &self.name;
+        std::iter::once(current.as_str())
+            .chain(self.aliases.iter()
+                .map(|x| x.name.as_str())
+                .filter(move |x| x != current))
+    }
+
+    /// Remembers that user was using `name` at `ts`
+    pub fn seen_as(&mut self, name: &str, ts: NaiveDateTime) {
+        match self.aliases.iter_mut().find(|x| x.name == name) {
+            Some(alias) => {
+                alias.first_seen = alias.first_seen.min(ts);
+                alias.last_seen = alias.last_seen.max(ts);
+            },
+            None => self.aliases.push(Alias {
+                name: name.to_string(),
+                first_seen: ts,
+                last_seen: ts
+            })
+        }
+    }
+}
+
+/// Comma separated kinds of events, like `comments,posts`
+pub fn parse_kinds(s: &str) -> Res<Vec<String>> {
+    let kinds: Vec<String> = s.split(',')
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(str::to_string)
+        .collect();
+    if kinds.is_empty() {
+        return Err(format_err!("No kinds specified: {}", s));
+    }
+    Ok(kinds)
+}
+
+/// Garbage in corrupted data is an error instead of panic
+fn to_datetime(ts: i64) -> Res<NaiveDateTime> {
+    NaiveDateTime::from_timestamp_opt(ts, 0).ok_or_else(|| format_err!("Invalid timestamp: {}", ts))
+}
+
+fn read_name<R: Read>(mut reader: R) -> Res<String> {
     let mut name = Vec::new();
     loop {
         let byte = reader.read_u8()?;
@@ -27,17 +268,102 @@ pub fn read_chunk<R: Read>(mut reader: R, seek: Option<usize>) -> Res<Option<Use
         }
         name.push(byte);
     }
-    let name = String::from_utf8(name)?;
+    Ok(String::from_utf8(name)?)
+}
+
+fn write_name<W: Write>(mut writer: W, name: &str) -> Res<()> {
+    for i in name.bytes() {
+        writer.write_u8(i)?;
+    }
+    writer.write_u8(0)?;
+    Ok(())
+}
+
+fn read_aliases<R: Read>(mut reader: R) -> Res<Vec<Alias>> {
+    let count = reader.read_u32::<LE>()?;
+    let mut aliases = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let name = read_name(&mut reader)?;
+        let first_seen = to_datetime(reader.read_i64::<LE>()?)?;
+        let last_seen = to_datetime(reader.read_i64::<LE>()?)?;
+        aliases.push(Alias { name, first_seen, last_seen });
+    }
+    Ok(aliases)
+}
+
+fn read_meta<R: Read>(mut reader: R) -> Res<StreamMeta> {
+    let kind = read_name(&mut reader)?;
+    let count = reader.read_u32::<LE>()?;
+    let mut attrs = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let flags = reader.read_u8()?;
+        let mut meta = Meta::default();
+        if flags & META_ID != 0 {
+            meta.id = Some(reader.read_i64::<LE>()?);
+        }
+        if flags & META_STORY != 0 {
+            meta.story_id = Some(reader.read_i64::<LE>()?);
+        }
+        if flags & META_RATING != 0 {
+            meta.rating = Some(reader.read_i32::<LE>()?);
+        }
+        attrs.push(meta);
+    }
+    Ok(StreamMeta { kind, attrs })
+}
+
+fn write_meta<W: Write>(mut writer: W, meta: &StreamMeta) -> Res<()> {
+    writer.write_i64::<LE>(TAG_META)?;
+    write_name(&mut writer, &meta.kind)?;
+    writer.write_u32::<LE>(meta.attrs.len() as u32)?;
+    for i in &meta.attrs {
+        let flags = i.id.map_or(0, |_| META_ID)
+            | i.story_id.map_or(0, |_| META_STORY)
+            | i.rating.map_or(0, |_| META_RATING);
+        writer.write_u8(flags)?;
+        if let Some(x) = i.id {
+            writer.write_i64::<LE>(x)?;
+        }
+        if let Some(x) = i.story_id {
+            writer.write_i64::<LE>(x)?;
+        }
+        if let Some(x) = i.rating {
+            writer.write_i32::<LE>(x)?;
+        }
+    }
+    Ok(())
+}
+
+fn read_stream<R: Read>(mut reader: R) -> Res<Stream> {
+    let kind = read_name(&mut reader)?;
+    let count = reader.read_u32::<LE>()?;
+    let mut events = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        events.push(to_datetime(reader.read_i64::<LE>()?)?);
+    }
+    Ok(Stream { kind, events })
+}
+
+pub fn read_chunk<R: Read>(mut reader: R, seek: Option<usize>) -> Res<Option<UserInfo>> {
+    let pikabu_id = reader.read_i64::<LE>()?;
+    let name = read_name(&mut reader)?;
     let mut comments = Vec::new();
+    let mut aliases = Vec::new();
+    let mut streams = Vec::new();
+    let mut meta = Vec::new();
     loop {
-        let ts = reader.read_i64::<LE>()?;
-        if ts == std::i64::MIN {
-            break;
+        match reader.read_i64::<LE>()? {
+            END => break,
+            TAG_ALIASES => aliases = read_aliases(&mut reader)?,
+            TAG_STREAM => streams.push(read_stream(&mut reader)?),
+            TAG_META => meta.push(read_meta(&mut reader)?),
+            tag if tag <= MAX_TAG => {
+                return Err(format_err!("Unknown tag {} in data, it is written by a newer version", tag - i64::MIN));
+            },
+            ts => comments.push(to_datetime(ts)?)
         }
-        let ts = NaiveDateTime::from_timestamp(ts, 0);
-        comments.push(ts);
     }
-    if pikabu_id == 0 && name.is_empty() && comments.is_empty() {
+    if pikabu_id == 0 && name.is_empty() && comments.is_empty() && streams.is_empty() {
         Ok(None)
     } else {
         Ok(Some(UserInfo {
@@ -45,6 +371,9 @@ pub fn read_chunk<R: Read>(mut reader: R, seek: Option<usize>) -> Res<Option<Use
             name,
             comments,
             seek,
+            aliases,
+            streams,
+            meta,
         }))
     }
 }
@@ -53,20 +382,37 @@ pub fn write_chunk<W: Write>(mut writer: W, info: Option<&UserInfo>) -> Res<()>
     match info {
         Some(info) => {
             writer.write_i64::<LE>(info.pikabu_id)?;
-            for i in info.name.bytes() {
-                writer.write_u8(i)?;
-            }
-            writer.write_u8(0)?;
+            write_name(&mut writer, &info.name)?;
             for i in &info.comments {
                 writer.write_i64::<LE>(i.timestamp())?
             }
-            writer.write_i64::<LE>(std::i64::MIN)?;
+            if !info.aliases.is_empty() {
+                writer.write_i64::<LE>(TAG_ALIASES)?;
+                writer.write_u32::<LE>(info.aliases.len() as u32)?;
+                for i in &info.aliases {
+                    write_name(&mut writer, &i.name)?;
+                    writer.write_i64::<LE>(i.first_seen.timestamp())?;
+                    writer.write_i64::<LE>(i.last_seen.timestamp())?;
+                }
+            }
+            for i in info.streams.iter().filter(|x| !x.events.is_empty()) {
+                writer.write_i64::<LE>(TAG_STREAM)?;
+                write_name(&mut writer, &i.kind)?;
+                writer.write_u32::<LE>(i.events.len() as u32)?;
+                for ts in &i.events {
+                    writer.write_i64::<LE>(ts.timestamp())?;
+                }
+            }
+            for i in info.meta.iter().filter(|x| !x.attrs.is_empty()) {
+                write_meta(&mut writer, i)?;
+            }
+            writer.write_i64::<LE>(END)?;
             Ok(())
         }
         None => {
             writer.write_i64::<LE>(0)?;
             writer.write_u8(0)?;
-            writer.write_i64::<LE>(std::i64::MIN)?;
+            writer.write_i64::<LE>(END)?;
             Ok(())
         }
     }
@@ -80,6 +426,34 @@ pub fn write_all<W: Write, D: SimpleData>(data: &D, mut writer: W) -> Res<()> {
     Ok(())
 }
 
+/// Line of index: id, offset and one of names. Current name goes first, former ones repeat id and offset
+#[derive(Debug, Clone)]
+pub struct IndexEntry {
+    pub pikabu_id: i64,
+    pub seek: usize,
+    pub name: String,
+}
+
+/// Lines with less than three fields, e.g. empty ones, are skipped. Bad id or offset is an error:
+/// maps from index are trusted to be complete, so a missing user would never be found
+pub fn read_index<R: BufRead>(reader: R) -> Res<Vec<IndexEntry>> {
+    let mut res = Vec::new();
+    for (n, ln) in reader.lines().enumerate() {
+        let ln = ln?;
+        let splitted: Vec<&str> = ln.splitn(3, ',').collect();
+        if splitted.len() != 3 {
+            continue;
+        }
+        let bad = |e: std::num::ParseIntError| format_err!("Line {} of index: {}", n + 1, e);
+        res.push(IndexEntry {
+            pikabu_id: splitted[0].parse().map_err(bad)?,
+            seek: splitted[1].parse().map_err(bad)?,
+            name: splitted[2].to_string(),
+        });
+    }
+    Ok(res)
+}
+
 #[derive(Clone, Copy)]
 pub struct CacheConfig {
     pub names: bool,
@@ -88,6 +462,24 @@ pub struct CacheConfig {
     pub prefer_seek: bool
 }
 
+/// Which maps contain every user from the file, so lookups in them can be trusted
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Maps {
+    pub names: bool,
+    pub ids: bool,
+    pub offsets: bool,
+}
+
+impl From<CacheConfig> for Maps {
+    fn from(cfg: CacheConfig) -> Self {
+        Maps {
+            names: cfg.names,
+            ids: cfg.ids,
+            offsets: cfg.offsets
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
 pub enum ReadConfig {
     None,
@@ -104,11 +496,7 @@ impl ReaderValue {
     pub fn into_cow<D: SimpleData>(self, data: &mut D) -> Option<Cow<UserInfo>> {
         match self {
             ReaderValue::Cached(idx) => {
-                if let Some(x) = data.get_cached(idx) {
-                    Some(Cow::Borrowed(x))
-                } else {
-                    None
-                }
+                data.get_cached(idx).map(Cow::Borrowed)
             },
             ReaderValue::Owned(ow) => Some(Cow::Owned(ow)),
             ReaderValue::None => None
@@ -130,25 +518,72 @@ impl ReaderValue {
     }
 }
 
+/// Reading stops at the first error without reporting it, so `finish` must be called after the loop.
+/// Debug builds check it when the reader is dropped
+#[must_use = "errors of reading are returned by finish()"]
 pub struct Reader<'a, T: SimpleData + Sized> {
     data: &'a mut T,
     config: ReadConfig,
-    val: ReaderValue
+    val: ReaderValue,
+    /// Reading stops on error, it is returned by `finish`
+    error: Option<failure::Error>,
+}
+
+impl<'a, T: SimpleData + Sized> Reader<'a, T> {
+    fn new(data: &'a mut T, config: ReadConfig) -> Self {
+        Reader {
+            data,
+            config,
+            val: ReaderValue::None,
+            error: None
+        }
+    }
+
+    /// Must be called after reading, otherwise part of data looks like all of it
+    pub fn finish(mut self) -> Res<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => Ok(())
+        }
+    }
+}
+
+impl<'a, T: SimpleData + Sized> Drop for Reader<'a, T> {
+    fn drop(&mut self) {
+        debug_assert!(
+            self.error.is_none() || std::thread::panicking(),
+            "Reader is dropped without finish(), error is lost: {:?}", self.error
+        );
+    }
 }
 
 impl<'a, T: SimpleData+Sized> StreamingIterator for Reader<'a, T> {
     type Item = UserInfo;
 
     fn advance(&mut self) {
-        let chunk = self.data.read_next();
-        self.val = if let Some(x) = chunk {
-            if let ReadConfig::Cache(cfg) = self.config {
-                ReaderValue::Cached(self.data.put_cache(x, cfg))
-            } else {
-                ReaderValue::Owned(x)
+        if self.error.is_some() {
+            self.val = ReaderValue::None;
+            return;
+        }
+        self.val = match self.data.read_next() {
+            Ok(Some(x)) => {
+                if let ReadConfig::Cache(cfg) = self.config {
+                    ReaderValue::Cached(self.data.put_cache(x, cfg))
+                } else {
+                    ReaderValue::Owned(x)
+                }
+            },
+            Ok(None) => {
+                if let ReadConfig::Cache(cfg) = self.config {
+                    // Whole file was read, so everything is in the cache now
+                    self.data.mark_complete(cfg.into());
+                }
+                ReaderValue::None
+            },
+            Err(e) => {
+                self.error = Some(e);
+                ReaderValue::None
             }
-        } else {
-            ReaderValue::None
         }
     }
 
@@ -163,7 +598,7 @@ impl<'a, T: SimpleData+Sized> StreamingIterator for Reader<'a, T> {
 
 pub trait SimpleData: Sized {
     type Reader;
-    type Reference: Copy;
+    type Reference: Copy + Eq + std::hash::Hash;
 
     fn get(&self, r: Self::Reference) -> Res<ReaderValue>;
 
@@ -172,15 +607,82 @@ pub trait SimpleData: Sized {
 
     // FIXME: Too concrete type
     fn iter_cached(&self) -> std::slice::Iter<UserInfo>;
-    fn iter_names(&self) -> std::collections::hash_map::Iter<String, Self::Reference>;
+    fn iter_names(&self) -> std::collections::hash_map::Iter<String, Vec<Self::Reference>>;
     fn iter_ids(&self) -> std::collections::hash_map::Iter<i64, Self::Reference>;
 
-    fn by_name(&mut self, name: &str) -> Res<ReaderValue>;
+    fn complete_maps(&self) -> Maps;
+    fn mark_complete(&mut self, maps: Maps);
+
+    /// Every user who has or had the name, so the result is the same as of a full scan
+    fn by_name(&mut self, name: &str) -> Res<Vec<ReaderValue>>;
     fn by_id(&mut self, id: i64) -> Res<ReaderValue>;
 
-    fn read_next(&mut self) -> Option<UserInfo>;
+    /// None after the terminating record. Corrupted or truncated data is an error
+    fn read_next(&mut self) -> Res<Option<UserInfo>>;
 
     fn get_reader(&mut self, config: ReadConfig) -> Res<Reader<Self>>;
+
+    /// Splits data into at most `parts` segments which can be read concurrently.
+    /// Segments follow in file order. Empty when data can be read only sequentially
+    fn split(&mut self, _parts: usize) -> Res<Vec<Segment>> {
+        Ok(Vec::new())
+    }
+}
+
+pub type Segment = Box<dyn Iterator<Item=Res<UserInfo>> + Send>;
+
+struct SegmentReader<R> {
+    reader: R,
+    end: u64,
+}
+
+impl<R: Read + Seek> Iterator for SegmentReader<R> {
+    type Item = Res<UserInfo>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let pos = match self.reader.stream_position() {
+            Ok(x) => x,
+            Err(e) => return Some(Err(e.into()))
+        };
+        if pos >= self.end {
+            return None;
+        }
+        read_chunk(&mut self.reader, Some(pos as usize)).map(count_read).transpose()
+    }
+}
+
+/// Finds start of the first record after `from`. Bytes of `END` may appear inside a record too,
+/// e.g. in id or name, so every candidate after them is confirmed by reading a record there.
+pub fn resync<R: Read + Seek>(reader: &mut R, from: u64) -> Res<Option<u64>> {
+    let mut buf = vec![0; 64 * 1024];
+    let mut pos = from;
+    loop {
+        reader.seek(SeekFrom::Start(pos))?;
+        // Last 8 bytes read
+        let mut window = 0u64;
+        let mut seen = 0;
+        let found = 'scan: loop {
+            // Offsets are not contiguous in block-compressed data, but single read never crosses a block
+            let start = reader.stream_position()?;
+            let num = reader.read(&mut buf)?;
+            if num == 0 {
+                return Ok(None);
+            }
+            for (i, b) in buf[..num].iter().enumerate() {
+                window = (window >> 8) | (u64::from(*b) << 56);
+                seen += 1;
+                if seen >= 8 && window as i64 == END {
+                    break 'scan start + i as u64 + 1;
+                }
+            }
+        };
+        // Not a boundary if record there is unreadable, look for the next candidate
+        reader.seek(SeekFrom::Start(found))?;
+        if read_chunk(&mut *reader, None).is_ok() {
+            return Ok(Some(found));
+        }
+        pos = found;
+    }
 }
 
 pub trait SeekableData {
@@ -198,34 +700,85 @@ pub trait SeekableData {
     fn by_offset(&self, offset: usize) -> Res<ReaderValue>;
 }
 
-impl<R, F> Data<R, F> {
+impl<R, F: Clone> Data<R, F> {
     pub fn new(reader: R) -> Self {
         Data {
             reader,
             is_reader_taken: false,
-            cached: Default::default(),
-            names: Default::default(),
-            ids: Default::default(),
-            offsets: Default::default(),
+            cache: Arc::new(Cache {
+                cached: Default::default(),
+                names: Default::default(),
+                ids: Default::default(),
+                offsets: Default::default(),
+                complete: Default::default(),
+            })
         }
     }
 
-    pub fn fill_cache(&mut self, names: HashMap<String, F>, ids: HashMap<i64, F>) {
-        self.names = names;
-        self.ids = ids;
+    /// Index contains all users, so both maps become complete
+    pub fn fill_cache(&mut self, names: HashMap<String, Vec<F>>, ids: HashMap<i64, F>) {
+        let cache = self.cache_mut();
+        cache.names = names;
+        cache.ids = ids;
+        cache.complete.names = true;
+        cache.complete.ids = true;
+    }
+
+    /// Cache is copied if it is shared with forks
+    pub fn cache_mut(&mut self) -> &mut Cache<F> {
+        Arc::make_mut(&mut self.cache)
     }
 }
 
-pub struct Data<R, F> {
-    reader: R,
-    is_reader_taken: bool,
+impl<R: Fork, F> Data<Mutex<R>, F> {
+    /// Another data with its own reader, sharing the cache with this one.
+    /// Forks are cheap, so they can be made for every request
+    pub fn fork(&self) -> Res<Self> {
+        Ok(Data {
+            reader: Mutex::new(self.reader.lock().fork()?),
+            is_reader_taken: false,
+            cache: self.cache.clone()
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct Cache<F> {
     pub cached: Vec<UserInfo>,
-    pub names: HashMap<String, F>,
+    /// Every owner of current or former name
+    pub names: HashMap<String, Vec<F>>,
     pub ids: HashMap<i64, F>,
     pub offsets: HashMap<usize, usize>,
+    complete: Maps,
+}
+
+pub struct Data<R, F> {
+    reader: R,
+    is_reader_taken: bool,
+    pub cache: Arc<Cache<F>>,
 }
 
 
+/// Name may belong to several users, e.g. someone took a name after its owner renamed.
+/// Users with the name as current one go first
+pub fn insert_name<F: PartialEq>(names: &mut HashMap<String, Vec<F>>, name: String, r: F, current: bool) {
+    let owners = names.entry(name).or_default();
+    if owners.contains(&r) {
+        return;
+    }
+    if current {
+        owners.insert(0, r);
+    } else {
+        owners.push(r);
+    }
+}
+
+pub fn insert_names<F: Copy + PartialEq>(names: &mut HashMap<String, Vec<F>>, info: &UserInfo, r: F) {
+    for (n, name) in info.names().enumerate() {
+        insert_name(names, name.to_lowercase(), r, n == 0);
+    }
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub enum SeekableRef {
     Seek(usize),
@@ -240,7 +793,7 @@ impl<R: Read> SimpleData for Data<R, CacheRef> {
     type Reference = CacheRef;
 
     fn get(&self, r: Self::Reference) -> Res<ReaderValue> {
-        Ok(if r.0 < self.cached.len() {
+        Ok(if r.0 < self.cache.cached.len() {
             ReaderValue::Cached(r.0)
         } else {
             ReaderValue::None
@@ -248,74 +801,83 @@ impl<R: Read> SimpleData for Data<R, CacheRef> {
     }
 
     fn get_cached(&self, idx: usize) -> Option<&UserInfo> {
-        self.cached.get(idx)
+        self.cache.cached.get(idx)
     }
 
     fn put_cache(&mut self, info: UserInfo, cfg: CacheConfig) -> usize {
-        let idx = self.cached.len();
+        let cache = self.cache_mut();
+        let idx = cache.cached.len();
 
         if cfg.offsets {
             if let Some(x) = info.seek {
-                self.offsets.insert(x, idx);
+                cache.offsets.insert(x, idx);
             }
         }
 
         if cfg.ids {
-            self.ids.insert(info.pikabu_id, CacheRef(idx));
+            cache.ids.insert(info.pikabu_id, CacheRef(idx));
         }
 
         if cfg.names {
-            self.names.insert(info.name.to_lowercase(), CacheRef(idx));
+            insert_names(&mut cache.names, &info, CacheRef(idx));
         }
 
-        self.cached.push(info);
+        cache.cached.push(info);
         idx
     }
 
     fn iter_cached(&self) -> std::slice::Iter<UserInfo> {
-        self.cached.iter()
+        self.cache.cached.iter()
     }
 
-    fn iter_names(&self) -> std::collections::hash_map::Iter<String, Self::Reference> {
-        self.names.iter()
+    fn iter_names(&self) -> std::collections::hash_map::Iter<String, Vec<Self::Reference>> {
+        self.cache.names.iter()
     }
 
     fn iter_ids(&self) -> std::collections::hash_map::Iter<i64, Self::Reference> {
-        self.ids.iter()
+        self.cache.ids.iter()
     }
 
-    fn by_name(&mut self, name: &str) -> Res<ReaderValue> {
-        Ok(match self.names.get(&name.to_lowercase()) {
-            Some(x) => ReaderValue::Cached(x.0),
-            None => ReaderValue::None
+    fn complete_maps(&self) -> Maps {
+        self.cache.complete
+    }
+
+    fn mark_complete(&mut self, maps: Maps) {
+        let cache = self.cache_mut();
+        cache.complete.names |= maps.names;
+        cache.complete.ids |= maps.ids;
+        cache.complete.offsets |= maps.offsets;
+    }
+
+    fn by_name(&mut self, name: &str) -> Res<Vec<ReaderValue>> {
+        Ok(match self.cache.names.get(&name.to_lowercase()) {
+            Some(x) => x.iter().map(|r| ReaderValue::Cached(r.0)).collect(),
+            None => Vec::new()
         })
     }
 
     fn by_id(&mut self, id: i64) -> Res<ReaderValue> {
-        Ok(match self.ids.get(&id) {
+        Ok(match self.cache.ids.get(&id) {
             Some(x) => ReaderValue::Cached(x.0),
             None => ReaderValue::None
         })
     }
 
-    fn read_next(&mut self) -> Option<UserInfo> {
-        if let Ok(Some(x)) = read_chunk(&mut self.reader, None) {
-            Some(x)
-        } else {
-            None
+    fn read_next(&mut self) -> Res<Option<UserInfo>> {
+        let user = count_read(read_chunk(&mut self.reader, None)?);
+        if user.is_none() {
+            // Checksums of compressed streams follow the data, they are checked only when read
+            std::io::copy(&mut self.reader, &mut std::io::sink())?;
         }
+        Ok(user)
     }
 
     fn get_reader(&mut self, config: ReadConfig) -> Res<Reader<Self>> {
         if self.is_reader_taken {
-            return Err(format_err!("You should not take reader multiple times"))
+            Err(format_err!("You should not take reader multiple times"))
         } else {
             self.is_reader_taken = true;
-            Ok(Reader {
-                data: self,
-                config,
-                val: ReaderValue::None
-            })
+            Ok(Reader::new(self, config))
         }
     }
 }
@@ -325,11 +887,7 @@ impl<R: Read+Seek, F> SeekableData for Data<Mutex<R>, F> {
         let mut reader = self.reader.lock();
         reader.seek(SeekFrom::Start(offset as u64))?;
         let r: &mut R = reader.borrow_mut();
-        Ok(if let Ok(Some(x)) = read_chunk(r, Some(offset)) {
-            Some(x)
-        } else {
-            None
-        })
+        read_chunk(r, Some(offset))
     }
 
     fn reset(&self) -> Res<()> {
@@ -338,7 +896,7 @@ impl<R: Read+Seek, F> SeekableData for Data<Mutex<R>, F> {
     }
 
     fn by_offset(&self, offset: usize) -> Res<ReaderValue> {
-        Ok(match self.offsets.get(&offset) {
+        Ok(match self.cache.offsets.get(&offset) {
             Some(x) => ReaderValue::Cached(*x),
             None => match self.read_at(offset)? {
                 Some(x) => ReaderValue::Owned(x),
@@ -348,7 +906,7 @@ impl<R: Read+Seek, F> SeekableData for Data<Mutex<R>, F> {
     }
 }
 
-impl<R: Read+Seek> SimpleData for Data<Mutex<R>, SeekableRef> where Self: SeekableData {
+impl<R: Read+Seek+Fork+Send+'static> SimpleData for Data<Mutex<R>, SeekableRef> where Self: SeekableData {
     type Reader = R;
     type Reference = SeekableRef;
 
@@ -360,77 +918,242 @@ impl<R: Read+Seek> SimpleData for Data<Mutex<R>, SeekableRef> where Self: Seekab
     }
 
     fn get_cached(&self, idx: usize) -> Option<&UserInfo> {
-        self.cached.get(idx)
+        self.cache.cached.get(idx)
     }
 
     fn iter_cached(&self) -> std::slice::Iter<UserInfo> {
-        self.cached.iter()
+        self.cache.cached.iter()
     }
 
-    fn iter_names(&self) -> std::collections::hash_map::Iter<String, Self::Reference> {
-        self.names.iter()
+    fn iter_names(&self) -> std::collections::hash_map::Iter<String, Vec<Self::Reference>> {
+        self.cache.names.iter()
     }
 
     fn iter_ids(&self) -> std::collections::hash_map::Iter<i64, Self::Reference> {
-        self.ids.iter()
+        self.cache.ids.iter()
+    }
+
+    fn complete_maps(&self) -> Maps {
+        self.cache.complete
+    }
+
+    fn mark_complete(&mut self, maps: Maps) {
+        let cache = self.cache_mut();
+        cache.complete.names |= maps.names;
+        cache.complete.ids |= maps.ids;
+        cache.complete.offsets |= maps.offsets;
     }
 
     fn put_cache(&mut self, info: UserInfo, cfg: CacheConfig) -> usize {
-        let idx = self.cached.len();
+        let cache = self.cache_mut();
+        let idx = cache.cached.len();
 
         if cfg.offsets {
             if let Some(x) = info.seek {
-                self.offsets.insert(x, idx);
+                cache.offsets.insert(x, idx);
             }
         }
 
         if cfg.ids {
-            self.ids.insert(info.pikabu_id, SeekableRef::Cached(idx));
+            cache.ids.insert(info.pikabu_id, SeekableRef::Cached(idx));
         }
 
         if cfg.names {
-            self.names.insert(info.name.to_lowercase(), SeekableRef::Cached(idx));
+            // Index may refer to the same user by offset, cached copy takes its place
+            if let Some(seek) = info.seek {
+                for name in info.names() {
+                    let owners = cache.names.get_mut(&name.to_lowercase());
+                    for r in owners.into_iter().flatten() {
+                        if *r == SeekableRef::Seek(seek) {
+                            *r = SeekableRef::Cached(idx);
+                        }
+                    }
+                }
+            }
+            insert_names(&mut cache.names, &info, SeekableRef::Cached(idx));
         }
 
-        self.cached.push(info);
+        cache.cached.push(info);
         idx
     }
 
-    fn by_name(&mut self, name: &str) -> Res<ReaderValue> {
-        match self.names.get(&name.to_lowercase()) {
-            Some(SeekableRef::Seek(s)) => self.read_at_val(*s),
-            Some(SeekableRef::Cached(x)) => Ok(ReaderValue::Cached(*x)),
-            None => Ok(ReaderValue::None)
-        }
+    fn by_name(&mut self, name: &str) -> Res<Vec<ReaderValue>> {
+        let owners = match self.cache.names.get(&name.to_lowercase()) {
+            Some(x) => x.clone(),
+            None => return Ok(Vec::new())
+        };
+        owners.into_iter().map(|r| self.get(r)).collect()
     }
 
     fn by_id(&mut self, id: i64) -> Res<ReaderValue> {
-        match self.ids.get(&id) {
+        match self.cache.ids.get(&id) {
             Some(SeekableRef::Seek(s)) => self.read_at_val(*s),
             Some(SeekableRef::Cached(x)) => Ok(ReaderValue::Cached(*x)),
             None => Ok(ReaderValue::None)
         }
     }
 
-    fn read_next(&mut self) -> Option<UserInfo> {
+    fn read_next(&mut self) -> Res<Option<UserInfo>> {
         let mut reader = self.reader.lock();
-        let seek = reader.seek(SeekFrom::Current(0))
+        // Unlike seek(...), it does not drop buffer of BufReader
+        let seek = reader.stream_position()
             .map(|x| x as usize)
             .ok();
         let r: &mut R = reader.borrow_mut();
-        if let Ok(Some(x)) = read_chunk(r, seek) {
-            Some(x)
-        } else {
-            None
-        }
+        Ok(count_read(read_chunk(r, seek)?))
     }
 
     fn get_reader(&mut self, config: ReadConfig) -> Res<Reader<Self>> {
         self.reset()?;
-        Ok(Reader {
-            data: self,
-            config,
-            val: ReaderValue::None
-        })
+        Ok(Reader::new(self, config))
+    }
+
+    fn split(&mut self, parts: usize) -> Res<Vec<Segment>> {
+        let base = self.reader.lock().fork()?;
+        let mut probe = base.fork()?;
+        let len = probe.seek(SeekFrom::End(0))?;
+
+        // Offsets from index are known boundaries, otherwise look for them
+        let mut known: Vec<u64> = self.cache.ids.values()
+            .filter_map(|x| match x {
+                SeekableRef::Seek(s) => Some(*s as u64),
+                SeekableRef::Cached(_) => None
+            })
+            .collect();
+        known.sort();
+
+        let mut starts = vec![0];
+        for i in 1..parts as u64 {
+            let approx = len * i / parts as u64;
+            let start = if known.is_empty() {
+                // Block-compressed data can't be entered at arbitrary position, so it is read sequentially
+                if probe.is_block_compressed() {
+                    break;
+                }
+                resync(&mut probe, approx)?
+            } else {
+                let idx = match known.binary_search(&approx) {
+                    Ok(x) | Err(x) => x
+                };
+                known.get(idx).cloned()
+            };
+            match start {
+                Some(x) if x > *starts.last().unwrap() => starts.push(x),
+                _ => {}
+            }
+        }
+
+        let mut res: Vec<Segment> = Vec::with_capacity(starts.len());
+        for (i, start) in starts.iter().enumerate() {
+            let mut reader = base.fork()?;
+            reader.seek(SeekFrom::Start(*start))?;
+            res.push(Box::new(SegmentReader {
+                reader,
+                end: starts.get(i + 1).cloned().unwrap_or(u64::MAX)
+            }));
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufReader;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    fn users(count: i64) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for id in 0..count {
+            let user = UserInfo {
+                name: format!("user{}", id),
+                pikabu_id: id,
+                seek: None,
+                comments: (0..100).map(|x| NaiveDateTime::from_timestamp(1_500_000_000 + x, 0)).collect(),
+                aliases: Vec::new(),
+                streams: Vec::new(),
+                meta: Vec::new(),
+            };
+            write_chunk(&mut buf, Some(&user)).unwrap();
+        }
+        write_chunk(&mut buf, None).unwrap();
+        buf
+    }
+
+    /// Users read before the error and result of `finish`
+    fn read_all<R: Read>(reader: R) -> (usize, Res<()>) {
+        let mut data: Data<_, CacheRef> = Data::new(reader);
+        let mut reader = data.get_reader(ReadConfig::None).unwrap();
+        let mut count = 0;
+        while reader.next().is_some() {
+            count += 1;
+        }
+        (count, reader.finish())
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn decoded(data: &[u8]) -> Box<dyn Read + Send + '_> {
+        crate::compress::decoder(BufReader::new(data)).unwrap()
+    }
+
+    #[test]
+    fn finish_reports_truncated_plain() {
+        let data = users(10);
+        let (count, res) = read_all(&data[..]);
+        assert_eq!(count, 10);
+        assert!(res.is_ok());
+
+        let (count, res) = read_all(&data[..data.len() / 2]);
+        assert!(count < 10);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn finish_reports_corrupted_plain() {
+        let mut data = users(10);
+        // Unknown tag instead of the first timestamp of the first user
+        let at = 8 + "user0".len() + 1;
+        (&mut data[at..]).write_i64::<LE>(TAG_META + 100).unwrap();
+        let (count, res) = read_all(&data[..]);
+        assert_eq!(count, 0);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn finish_reports_truncated_gzip() {
+        let data = gzip(&users(10));
+        let (count, res) = read_all(decoded(&data));
+        assert_eq!(count, 10);
+        assert!(res.is_ok());
+
+        let (_, res) = read_all(decoded(&data[..data.len() - 20]));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn finish_reports_corrupted_gzip() {
+        let mut data = gzip(&users(10));
+        // Checksum of decompressed data is in the footer
+        let at = data.len() - 8;
+        data[at] ^= 0xff;
+        let (count, res) = read_all(decoded(&data));
+        assert!(count <= 10);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn index_skips_short_lines_and_fails_on_bad_numbers() {
+        let index = read_index("1,10,user1\n\n2,20,user,with,commas\n".as_bytes()).unwrap();
+        assert_eq!(index.len(), 2);
+        assert_eq!((index[1].pikabu_id, index[1].seek, index[1].name.as_str()), (2, 20, "user,with,commas"));
+
+        let err = read_index("1,10,user1\nx,20,user2\n".as_bytes()).unwrap_err();
+        assert!(err.to_string().starts_with("Line 2 of index"));
     }
 }
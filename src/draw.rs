@@ -1,4 +1,4 @@
-use chrono::{NaiveDateTime, Datelike, NaiveDate, Timelike, Duration};
+use chrono::{NaiveDateTime, Datelike, NaiveDate, Timelike, Duration, Weekday};
 use image::{RgbImage, Rgb};
 use crate::Res;
 use font8x8::UnicodeFonts;
@@ -9,7 +9,7 @@ fn draw_text(buf: &mut RgbImage, color: Rgb<u8>, mut x_base: u32, y_orig: u32, t
     for ch in text.chars() {
         let letter = font8x8::LATIN_FONTS.get(ch)
             .or_else(|| font8x8::BASIC_FONTS.get(ch))
-            .unwrap_or_else(|| [255; 8]);
+            .unwrap_or([255; 8]);
         {
             let mut y = y_orig;
             for row in &letter[..] {
@@ -32,7 +32,9 @@ const WIDTH: usize = 60*24;
 
 struct Day {
     date: NaiveDate,
-    points: [u8; WIDTH]
+    points: [u8; WIDTH],
+    /// Bit for every layer with points in the minute
+    layers: [u8; WIDTH]
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -45,38 +47,217 @@ struct Month {
 
 pub struct Generated {
     days: Vec<Day>,
-    months: Vec<Month>
+    months: Vec<Month>,
+    /// Colors depend on layers instead of number of points
+    overlay: bool
+}
+
+/// Number of kinds which can be overlaid on dots
+pub const MAX_LAYERS: usize = 3;
+
+/// Colors of overlaid kinds, in order. Minutes with several kinds get mixed colors
+const LAYER_COLORS: [[u8; 3]; MAX_LAYERS] = [
+    [0x00, 0xFF, 0x00], // Lime
+    [0xFF, 0x00, 0x00], // Red
+    [0x00, 0x80, 0xFF], // Blue
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Palette {
+    Normal,
+    Comments,
+    Posts,
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        #[cfg(not(any(feature="normal", feature="comments", feature="posts")))]
+        compile_error!("One and only one of features must be specified: normal, comments, posts");
+        #[cfg(feature="normal")]
+        let palette = {
+            #[cfg(any(feature="comments", feature="posts"))]
+            compile_error!("One and only one of features must be specified: normal, comments, posts");
+            Palette::Normal
+        };
+        #[cfg(feature="comments")]
+        let palette = {
+            #[cfg(any(feature="normal", feature="posts"))]
+            compile_error!("One and only one of features must be specified: normal, comments, posts");
+            Palette::Comments
+        };
+        #[cfg(feature="posts")]
+        let palette = {
+            #[cfg(any(feature="normal", feature="comments"))]
+            compile_error!("One and only one of features must be specified: normal, comments, posts");
+            Palette::Posts
+        };
+        palette
+    }
+}
+
+impl std::str::FromStr for Palette {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Res<Self> {
+        match s {
+            "normal" => Ok(Palette::Normal),
+            "comments" => Ok(Palette::Comments),
+            "posts" => Ok(Palette::Posts),
+            _ => Err(format_err!("Unknown palette: {}", s))
+        }
+    }
+}
+
+impl Palette {
+    /// Largest number of points which has its own color
+    pub fn levels(self) -> u8 {
+        match self {
+            Palette::Normal => 17,
+            Palette::Comments => 99,
+            Palette::Posts => 31,
+        }
+    }
+
+    /// Color of `count` relative to `max`, so any counts can be drawn with the palette
+    fn scaled(self, count: u32, max: u32) -> Rgb<u8> {
+        if count == 0 || max == 0 {
+            return self.color(0);
+        }
+        let level = (u64::from(count) * u64::from(self.levels()) + u64::from(max) - 1) / u64::from(max);
+        self.color(level.max(1) as u8)
+    }
+
+    /// Color of a minute with `p` points
+    pub fn color(self, p: u8) -> Rgb<u8> {
+        match self {
+            Palette::Normal => Rgb(match p {
+                // Step 1
+                0 => [0x00, 0x00, 0x00], // Black
+                1 => [0x00, 0xFF, 0x00], // Lime
+                2 => [0xFF, 0xFF, 0x00], // Yellow
+                3 => [0x00, 0xFF, 0xFF], // Cyan
+                4 => [0xFF, 0x00, 0x00],  // Red
+                5 => [0x3C, 0xB3, 0x71],  // DarkGreen
+                6 => [0x00, 0xFA, 0x9A], // MediumSpringGreen
+                7 => [0xAD, 0xFF, 0x2F], // GreenYellow
+                8 => [0xFF, 0xD7, 0x00], // Gold
+                9 => [0xFF, 0xFF, 0x00],  // Yellow
+                10 => [0xFF, 0xA5, 0x00], // Orange
+                11 => [0xFF, 0x7F, 0x50], // Coral
+                12 => [0xFA, 0x80, 0x72], // Salmon
+                13 => [0xDC, 0x14, 0x3C], // Crimson
+                14 => [0xFF, 0x14, 0x93], // Pink
+                15 => [0xFF, 0x00, 0xFF], // Magenta
+                16 => [0x8A, 0x2B, 0xE2], // BlueViolet
+                17 => [0x80, 0x00, 0x80], // Purple
+                // Fallback
+                18..=255 => [0xFF, 0xFF, 0xFF],  // White
+            }),
+            Palette::Comments => Rgb(match p {
+                // Step 5
+                0 => [0x00, 0x00, 0x00], // Black
+                1..=5 => [0x00, 0xB3, 0x00], // 60% green
+                6..=9 => [0x9A, 0x9A, 0x00], // 60% yellow
+                10..=14 => [0x00, 0xFF, 0xFF], // Cyan
+                15 => [0x3C, 0xB3, 0x71],  // DarkGreen
+                16..=21 => [0x00, 0xFA, 0x9A], // MediumSpringGreen
+                22..=27 => [0x00, 0xFF, 0x00], // Lime
+                28..=33 => [0xAD, 0xFF, 0x2F], // GreenYellow
+                34..=39 => [0xFF, 0xD7, 0x00], // Gold
+                40..=45 => [0xFF, 0xFF, 0x00],  // Yellow
+                46..=51 => [0xFF, 0xA5, 0x00], // Orange
+                52..=57 => [0xFF, 0x7F, 0x50], // Coral
+                58..=63 => [0xFA, 0x80, 0x72], // Salmon
+                64..=69 => [0xDC, 0x14, 0x3C], // Crimson
+                70..=75 => [0xFF, 0x00, 0x00], // Red
+                76..=81 => [0xFF, 0x14, 0x93], // Pink
+                82..=87 => [0xFF, 0x00, 0xFF], // Magenta
+                88..=93 => [0x8A, 0x2B, 0xE2], // BlueViolet
+                94..=99 => [0x80, 0x00, 0x80], // Purple
+                // Fallback
+                100..=255 => [0xFF, 0xFF, 0xFF],  // White
+            }),
+            Palette::Posts => Rgb(match p {
+                // Step 1
+                0 => [0x00, 0x00, 0x00], // Black
+                1 => [0x00, 0xB3, 0x00], // 60% green
+                2 => [0x9A, 0x9A, 0x00], // 60% yellow
+                3 => [0x00, 0xFF, 0xFF], // Cyan
+                4 => [0x7F, 0xFF, 0xD4], // Aquamarine
+                5 => [0x3C, 0xB3, 0x71],  // DarkGreen
+                6 => [0x00, 0xFA, 0x9A], // MediumSpringGreen
+                7 => [0x00, 0xFF, 0x00], // Lime
+                // Step 2
+                8..=9 => [0xAD, 0xFF, 0x2F], // GreenYellow
+                10..=11 => [0xFF, 0xD7, 0x00], // Gold
+                12..=13 => [0xFF, 0xFF, 0x00],  // Yellow
+                14..=15 => [0xFF, 0xA5, 0x00], // Orange
+                16..=17 => [0xFF, 0x7F, 0x50], // Coral
+                18..=19 => [0xFA, 0x80, 0x72], // Salmon
+                20..=21 => [0xDC, 0x14, 0x3C], // Crimson
+                22..=23 => [0xFF, 0x00, 0x00], // Red
+                24..=25 => [0xFF, 0x14, 0x93], // Pink
+                26..=27 => [0xFF, 0x00, 0xFF], // Magenta
+                28..=29 => [0x8A, 0x2B, 0xE2], // BlueViolet
+                30..=31 => [0x80, 0x00, 0x80], // Purple
+                // Fallback
+                32..=255 => [0xFF, 0xFF, 0xFF],  // White
+            }),
+        }
+    }
 }
 
 // TODO: Generate merged image of all users
 // This will use a lot of memory, so it requires somehow append data to existing Generated
 
 pub fn generate(points: &[NaiveDateTime]) -> Generated {
-    if points.is_empty() {
-        return Generated {
+    build(points.iter().map(|x| (*x, 1)), false)
+}
+
+/// Every layer is drawn with its own color, layers must be sorted
+pub fn overlay(layers: &[&[NaiveDateTime]]) -> Res<Generated> {
+    if layers.len() > MAX_LAYERS {
+        return Err(format_err!("At most {} kinds can be overlaid", MAX_LAYERS));
+    }
+    let mut points: Vec<(NaiveDateTime, u8)> = layers.iter()
+        .enumerate()
+        .flat_map(|(i, x)| x.iter().map(move |p| (*p, 1 << i)))
+        .collect();
+    points.sort();
+    Ok(build(points.into_iter(), true))
+}
+
+/// Points are paired with bit of their layer
+fn build<I: Iterator<Item=(NaiveDateTime, u8)>>(points: I, overlay: bool) -> Generated {
+    let mut points = points.peekable();
+    let first = match points.peek() {
+        Some((x, _)) => *x,
+        None => return Generated {
             days: Vec::new(),
-            months: Vec::new()
+            months: Vec::new(),
+            overlay
         }
-    }
-    let first = points[0];
+    };
     let one_day = Duration::days(1);
 
     let mut month_start = 0;
     let mut days = Vec::new();
     let mut last_day = Day {
         date: first.date() - one_day,
-        points: [0; WIDTH]
+        points: [0; WIDTH],
+        layers: [0; WIDTH]
     };
     let mut months = Vec::new();
 
-    for p in points {
+    for (p, layer) in points {
         assert!(p.date() >= last_day.date);
         let dt = p.date();
         if dt != last_day.date {
             assert!(p.date() > last_day.date);
             let old_day = std::mem::replace(&mut last_day, Day {
                 date: dt,
-                points: [0; WIDTH]
+                points: [0; WIDTH],
+                layers: [0; WIDTH]
             });
             let mut insert_day = |d: Day| {
                 if d.date.day() == 1 {
@@ -100,7 +281,8 @@ pub fn generate(points: &[NaiveDateTime]) -> Generated {
             while d < dt {
                 insert_day(Day {
                     date: d,
-                    points: [0; WIDTH]
+                    points: [0; WIDTH],
+                    layers: [0; WIDTH]
                 });
                 d += one_day;
             }
@@ -108,18 +290,34 @@ pub fn generate(points: &[NaiveDateTime]) -> Generated {
         let position = (p.hour()*60 + p.minute()) as usize;
         debug_assert!(position < 60*24);
         //unsafe{std::intrinsics::assume(position < 60*24)};
-        last_day.points[position] += 1;
+        // Counts past the last level are drawn the same, so they only must not overflow
+        last_day.points[position] = last_day.points[position].saturating_add(1);
+        last_day.layers[position] |= layer;
     }
 
     Generated {
         days,
-        months
+        months,
+        overlay
     }
 }
 
+/// Sum of colors of layers
+fn mix(layers: u8) -> Rgb<u8> {
+    let mut res = [0u8; 3];
+    for (i, color) in LAYER_COLORS.iter().enumerate() {
+        if layers & (1 << i) != 0 {
+            for (c, x) in res.iter_mut().zip(color) {
+                *c = c.saturating_add(*x);
+            }
+        }
+    }
+    Rgb(res)
+}
+
 impl Generated {
     // TODO: Optimize and remove second pass. But it is very bad idea
-    pub fn into_image(self, timezone: i8) -> Res<RgbImage> {
+    pub fn into_image(self, timezone: i8, palette: Palette) -> Res<RgbImage> {
         const OFFSET_X: u32 = 8*3 + 1;
         const OFFSET_Y: u32 = 8*2 + 1;
         const GRAY: [u8; 3] = [0x40, 0x40, 0x40];
@@ -131,101 +329,12 @@ impl Generated {
             vec![0; (3*width*height) as usize]
         ).ok_or_else(|| format_err!("Unable to create RgbImage. Very strange"))?;
 
+        let overlay = self.overlay;
         let mut y = OFFSET_Y;
         for d in self.days {
             let mut x = OFFSET_X;
-            for p in d.points.iter() {
-                #[cfg(not(any(feature="normal", feature="comments", feature="posts")))]
-                compile_error!("One and only one of features must be specified: normal, comments, posts");
-                #[cfg(feature="normal")]
-                let color = {
-                    #[cfg(any(feature="comments", feature="posts"))]
-                    compile_error!("One and only one of features must be specified: normal, comments, posts");
-                    Rgb(match p {
-                        // Step 1
-                        0 => [0x00, 0x00, 0x00], // Black
-                        1 => [0x00, 0xFF, 0x00], // Lime
-                        2 => [0xFF, 0xFF, 0x00], // Yellow
-                        3 => [0x00, 0xFF, 0xFF], // Cyan
-                        4 => [0xFF, 0x00, 0x00],  // Red
-                        5 => [0x3C, 0xB3, 0x71],  // DarkGreen
-                        6 => [0x00, 0xFA, 0x9A], // MediumSpringGreen
-                        7 => [0xAD, 0xFF, 0x2F], // GreenYellow
-                        8 => [0xFF, 0xD7, 0x00], // Gold
-                        9 => [0xFF, 0xFF, 0x00],  // Yellow
-                        10 => [0xFF, 0xA5, 0x00], // Orange
-                        11 => [0xFF, 0x7F, 0x50], // Coral
-                        12 => [0xFA, 0x80, 0x72], // Salmon
-                        13 => [0xDC, 0x14, 0x3C], // Crimson
-                        14 => [0xFF, 0x14, 0x93], // Pink
-                        15 => [0xFF, 0x00, 0xFF], // Magenta
-                        16 => [0x8A, 0x2B, 0xE2], // BlueViolet
-                        17 => [0x80, 0x00, 0x80], // Purple
-                        // Fallback
-                        18..=255 => [0xFF, 0xFF, 0xFF],  // White
-                    })
-                };
-                #[cfg(feature="comments")]
-                let color = {
-                    #[cfg(any(feature="normal", feature="posts"))]
-                    compile_error!("One and only one of features must be specified: normal, comments, posts");
-                    Rgb(match p {
-                        // Step 5
-                        0 => [0x00, 0x00, 0x00], // Black
-                        1..=5 => [0x00, 0xB3, 0x00], // 60% green
-                        5..10 => [0x9A, 0x9A, 0x00], // 60% yellow
-                        10..15 => [0x00, 0xFF, 0xFF], // Cyan
-                        4..=9 => [0x7F, 0xFF, 0xD4], // Aquamarine
-                        10..=15 => [0x3C, 0xB3, 0x71],  // DarkGreen
-                        16..=21 => [0x00, 0xFA, 0x9A], // MediumSpringGreen
-                        22..=27 => [0x00, 0xFF, 0x00], // Lime
-                        28..=33 => [0xAD, 0xFF, 0x2F], // GreenYellow
-                        34..=39 => [0xFF, 0xD7, 0x00], // Gold
-                        40..=45 => [0xFF, 0xFF, 0x00],  // Yellow
-                        46..=51 => [0xFF, 0xA5, 0x00], // Orange
-                        52..=57 => [0xFF, 0x7F, 0x50], // Coral
-                        58..=63 => [0xFA, 0x80, 0x72], // Salmon
-                        64..=69 => [0xDC, 0x14, 0x3C], // Crimson
-                        70..=75 => [0xFF, 0x00, 0x00], // Red
-                        76..=81 => [0xFF, 0x14, 0x93], // Pink
-                        82..=87 => [0xFF, 0x00, 0xFF], // Magenta
-                        88..=93 => [0x8A, 0x2B, 0xE2], // BlueViolet
-                        94..=99 => [0x80, 0x00, 0x80], // Purple
-                        // Fallback
-                        100..=255 => [0xFF, 0xFF, 0xFF],  // White
-                    })
-                };
-                #[cfg(feature="posts")]
-                let color = {
-                    #[cfg(any(feature="normal", feature="comments"))]
-                    compile_error!("One and only one of features must be specified: normal, comments, posts");
-                    Rgb(match p {
-                        // Step 1
-                        0 => [0x00, 0x00, 0x00], // Black
-                        1 => [0x00, 0xB3, 0x00], // 60% green
-                        2 => [0x9A, 0x9A, 0x00], // 60% yellow
-                        3 => [0x00, 0xFF, 0xFF], // Cyan
-                        4 => [0x7F, 0xFF, 0xD4], // Aquamarine
-                        5 => [0x3C, 0xB3, 0x71],  // DarkGreen
-                        6 => [0x00, 0xFA, 0x9A], // MediumSpringGreen
-                        7 => [0x00, 0xFF, 0x00], // Lime
-                        // Step 2
-                        8..=9 => [0xAD, 0xFF, 0x2F], // GreenYellow
-                        10..=11 => [0xFF, 0xD7, 0x00], // Gold
-                        12..=13 => [0xFF, 0xFF, 0x00],  // Yellow
-                        14..=15 => [0xFF, 0xA5, 0x00], // Orange
-                        16..=17 => [0xFF, 0x7F, 0x50], // Coral
-                        18..=19 => [0xFA, 0x80, 0x72], // Salmon
-                        20..=21 => [0xDC, 0x14, 0x3C], // Crimson
-                        22..=23 => [0xFF, 0x00, 0x00], // Red
-                        24..=25 => [0xFF, 0x14, 0x93], // Pink
-                        26..=27 => [0xFF, 0x00, 0xFF], // Magenta
-                        28..=29 => [0x8A, 0x2B, 0xE2], // BlueViolet
-                        30..=31 => [0x80, 0x00, 0x80], // Purple
-                        // Fallback
-                        32..=255 => [0xFF, 0xFF, 0xFF],  // White
-                    })
-                };
+            for (p, layers) in d.points.iter().zip(d.layers.iter()) {
+                let color = if overlay { mix(*layers) } else { palette.color(*p) };
                 img.put_pixel(x, y, color);
                 #[cfg(feature="pluses")]
                 {
@@ -296,3 +405,183 @@ impl Generated {
         Ok(img)
     }
 }
+
+/// Kind of image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Chart {
+    /// Every minute of every day, see `generate`
+    #[default]
+    Dots,
+    /// 7x24 heatmap of hours of week
+    WeekHour,
+    /// 24 bars, one for every hour of day
+    Hourly,
+}
+
+impl std::str::FromStr for Chart {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Res<Self> {
+        match s {
+            "dots" => Ok(Chart::Dots),
+            "weekhour" => Ok(Chart::WeekHour),
+            "hourly" => Ok(Chart::Hourly),
+            _ => Err(format_err!("Unknown chart: {}", s))
+        }
+    }
+}
+
+/// Points must be sorted
+pub fn draw(points: &[NaiveDateTime], chart: Chart, timezone: i8, palette: Palette) -> Res<RgbImage> {
+    match chart {
+        Chart::Dots => generate(points).into_image(timezone, palette),
+        Chart::WeekHour => week_hour(points, timezone, palette),
+        Chart::Hourly => hourly(points, timezone, palette),
+    }
+}
+
+/// Several layers are overlaid on dots. Other charts can't show them apart, so layers are joined
+pub fn draw_layers(layers: &[Vec<NaiveDateTime>], chart: Chart, timezone: i8, palette: Palette) -> Res<RgbImage> {
+    match (layers, chart) {
+        ([points], _) => draw(points, chart, timezone, palette),
+        (_, Chart::Dots) => {
+            let layers: Vec<&[NaiveDateTime]> = layers.iter().map(|x| &x[..]).collect();
+            overlay(&layers)?.into_image(timezone, palette)
+        },
+        _ => draw(&crate::join_sorted(layers.iter().map(|x| x.iter().cloned())), chart, timezone, palette)
+    }
+}
+
+/// Unlike dots, hours are not just relabeled, because points may move to another day
+fn shifted(points: &[NaiveDateTime], timezone: i8) -> impl Iterator<Item=NaiveDateTime> + '_ {
+    let shift = Duration::hours(timezone.into());
+    points.iter().map(move |x| *x + shift)
+}
+
+fn blank(width: u32, height: u32) -> Res<RgbImage> {
+    RgbImage::from_raw(width, height, vec![0; (3*width*height) as usize])
+        .ok_or_else(|| format_err!("Unable to create RgbImage. Very strange"))
+}
+
+fn week_hour(points: &[NaiveDateTime], timezone: i8, palette: Palette) -> Res<RgbImage> {
+    const CELL: u32 = 16;
+    const OFFSET_X: u32 = 8*3 + 1;
+    const OFFSET_Y: u32 = 8 + 1;
+    const WEEKDAYS: [Weekday; 7] = [
+        Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu, Weekday::Fri, Weekday::Sat, Weekday::Sun
+    ];
+
+    let mut counts = [[0u32; 24]; 7];
+    for p in shifted(points, timezone) {
+        counts[p.weekday().num_days_from_monday() as usize][p.hour() as usize] += 1;
+    }
+    let max = counts.iter().flatten().cloned().max().unwrap_or(0);
+
+    let mut img = blank(OFFSET_X + 24*CELL, OFFSET_Y + 7*CELL)?;
+    for (day, hours) in counts.iter().enumerate() {
+        let y = OFFSET_Y + day as u32 * CELL;
+        draw_text(&mut img, Rgb([255, 255, 255]), 0, y + (CELL - 8) / 2, &format!("{:?}", WEEKDAYS[day]));
+        for (hour, cnt) in hours.iter().enumerate() {
+            let x = OFFSET_X + hour as u32 * CELL;
+            let color = palette.scaled(*cnt, max);
+            // One pixel is left as a grid
+            for dy in 0..CELL-1 {
+                for dx in 0..CELL-1 {
+                    img.put_pixel(x + dx, y + dy, color);
+                }
+            }
+        }
+    }
+    for hour in (0..24).step_by(2) {
+        draw_text(&mut img, Rgb([255, 255, 255]), OFFSET_X + hour * CELL, 0, &format!("{:02}", hour));
+    }
+    Ok(img)
+}
+
+fn hourly(points: &[NaiveDateTime], timezone: i8, palette: Palette) -> Res<RgbImage> {
+    const BAR: u32 = 20;
+    const HEIGHT: u32 = 200;
+    const OFFSET_Y: u32 = 8 + 1;
+    const GRAY: [u8; 3] = [0x40, 0x40, 0x40];
+
+    let mut counts = [0u32; 24];
+    for p in shifted(points, timezone) {
+        counts[p.hour() as usize] += 1;
+    }
+    let max = counts.iter().cloned().max().unwrap_or(0);
+
+    let mut img = blank(24*BAR, OFFSET_Y + HEIGHT + OFFSET_Y)?;
+    draw_text(&mut img, Rgb([255, 255, 255]), 0, 0, &format!("max: {}", max));
+    for x in 0..24*BAR {
+        img.put_pixel(x, OFFSET_Y + HEIGHT - 1, Rgb(GRAY));
+    }
+    for (hour, cnt) in counts.iter().enumerate() {
+        let x = hour as u32 * BAR;
+        let height = if max == 0 { 0 } else { (u64::from(*cnt) * u64::from(HEIGHT) / u64::from(max)) as u32 };
+        let color = palette.scaled(*cnt, max);
+        for dy in 0..height {
+            for dx in 1..BAR-1 {
+                img.put_pixel(x + dx, OFFSET_Y + HEIGHT - 1 - dy, color);
+            }
+        }
+        if hour % 2 == 0 {
+            draw_text(&mut img, Rgb([255, 255, 255]), x + (BAR - 16) / 2, OFFSET_Y + HEIGHT + 1, &format!("{:02}", hour));
+        }
+    }
+    Ok(img)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PALETTES: [Palette; 3] = [Palette::Normal, Palette::Comments, Palette::Posts];
+
+    #[test]
+    fn level_boundaries() {
+        const WHITE: Rgb<u8> = Rgb([0xFF, 0xFF, 0xFF]);
+        for palette in &PALETTES {
+            assert_eq!(palette.color(0), Rgb([0, 0, 0]));
+            assert_ne!(palette.color(palette.levels()), WHITE);
+            assert_eq!(palette.color(palette.levels() + 1), WHITE);
+            assert_eq!(palette.color(255), WHITE);
+        }
+        // Step 5 of comments, with a single DarkGreen level between
+        let comments = |p| Palette::Comments.color(p).0;
+        assert_eq!(comments(1), comments(5));
+        assert_ne!(comments(5), comments(6));
+        assert_eq!(comments(6), comments(9));
+        assert_eq!((comments(10), comments(14)), ([0x00, 0xFF, 0xFF], [0x00, 0xFF, 0xFF]));
+        assert_eq!(comments(15), [0x3C, 0xB3, 0x71]);
+        assert_eq!((comments(16), comments(21)), ([0x00, 0xFA, 0x9A], [0x00, 0xFA, 0x9A]));
+        assert_eq!((comments(94), comments(99)), ([0x80, 0x00, 0x80], [0x80, 0x00, 0x80]));
+    }
+
+    #[test]
+    fn scaled_colors() {
+        for palette in &PALETTES {
+            let levels = u32::from(palette.levels());
+            assert_eq!(palette.scaled(0, 0), palette.color(0));
+            assert_eq!(palette.scaled(0, 1000), palette.color(0));
+            // Any point is visible, the maximum is the last level
+            assert_eq!(palette.scaled(1, 1_000_000), palette.color(1));
+            assert_eq!(palette.scaled(1000, 1000), palette.color(palette.levels()));
+            assert_eq!(palette.scaled(u32::MAX, u32::MAX), palette.color(palette.levels()));
+            // Small counts keep their own colors
+            for count in 1..=levels {
+                assert_eq!(palette.scaled(count, levels), palette.color(count as u8));
+            }
+        }
+    }
+
+    #[test]
+    fn many_points_in_a_minute() {
+        let day = NaiveDate::from_ymd(2017, 7, 10);
+        let mut points = vec![day.and_hms(10, 0, 0); 300];
+        points.push(day.succ().and_hms(0, 0, 0));
+        let generated = generate(&points);
+        // Days start from the one before the first point
+        assert_eq!(generated.days[1].points[10 * 60], 255);
+        generated.into_image(0, Palette::Normal).unwrap();
+    }
+}
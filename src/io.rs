@@ -0,0 +1,162 @@
+// Inputs and outputs given in command line. Missing path or `-` means stdin or stdout
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{self, BufReader, BufWriter};
+use std::path::PathBuf;
+use crate::compress::{self, Codec, Encoder};
+use crate::progress::{ReaderWrapper, Progress};
+use crate::shared::{SharedFile, DataFile};
+use crate::Res;
+
+fn is_stdio(path: Option<&OsStr>) -> bool {
+    path.map_or(true, |x| x == "-")
+}
+
+/// Sequential input, decompressed if needed
+pub struct Input {
+    reader: Box<dyn Read + Send>,
+    /// Position is counted before decompression, so total is known for files
+    pub bar: Progress,
+}
+
+impl Input {
+    fn new<R: Read + Send + 'static>(raw: ReaderWrapper<R>) -> Res<Self> {
+        let bar = raw.bar.clone();
+        Ok(Input {
+            reader: compress::decoder(BufReader::new(raw))?,
+            bar
+        })
+    }
+
+    pub fn open(path: Option<&OsStr>) -> Res<Self> {
+        if is_stdio(path) {
+            Self::new(ReaderWrapper::new(io::stdin(), 0))
+        } else {
+            Self::new(ReaderWrapper::from_file(File::open(path.unwrap())?))
+        }
+    }
+}
+
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let num = self.reader.read(buf)?;
+        self.bar.inc_decompressed(num as u64);
+        Ok(num)
+    }
+}
+
+/// Data file, read with seeks when possible
+pub enum DataInput {
+    /// Plain or block-compressed file
+    Seekable(ReaderWrapper<SharedFile>),
+    /// Stdin or file compressed as a single stream
+    Stream(Input),
+}
+
+impl DataInput {
+    pub fn open(path: Option<&OsStr>) -> Res<Self> {
+        if is_stdio(path) {
+            return Ok(DataInput::Stream(Input::open(None)?));
+        }
+        let mut reader = ReaderWrapper::from_shared(SharedFile::open(path.unwrap())?);
+        Ok(if Codec::detect_seek(&mut reader)?.is_seekable() {
+            DataInput::Seekable(reader)
+        } else {
+            DataInput::Stream(Input::new(reader)?)
+        })
+    }
+
+    pub fn bar(&self) -> Progress {
+        match self {
+            DataInput::Seekable(x) => x.bar.clone(),
+            DataInput::Stream(x) => x.bar.clone(),
+        }
+    }
+
+    /// For commands which need seeks, stream is an error
+    pub fn seekable(self) -> Res<ReaderWrapper<DataFile<ReaderWrapper<SharedFile>>>> {
+        match self {
+            DataInput::Seekable(x) => {
+                let bar = x.bar.clone();
+                Ok(ReaderWrapper::decompressed(DataFile::new(x)?, bar))
+            },
+            DataInput::Stream(_) => Err(format_err!("Data must be a file without compression or with bgzf"))
+        }
+    }
+}
+
+/// Data for commands which read it twice. Stdin is read once, so it is copied to a temporary file
+pub struct Reopenable {
+    path: PathBuf,
+    temporary: bool,
+}
+
+impl Reopenable {
+    pub fn new(path: Option<&OsStr>) -> Res<Self> {
+        if !is_stdio(path) {
+            return Ok(Reopenable { path: PathBuf::from(path.unwrap()), temporary: false });
+        }
+        let res = Reopenable {
+            path: std::env::temp_dir().join(format!("pikadots-stdin-{}", std::process::id())),
+            temporary: true
+        };
+        let mut input = ReaderWrapper::new(io::stdin(), 0).message("copying stdin");
+        let mut copy = BufWriter::new(File::create(&res.path)?);
+        io::copy(&mut input, &mut copy)?;
+        copy.flush()?;
+        input.bar.finish();
+        Ok(res)
+    }
+
+    pub fn open(&self) -> Res<DataInput> {
+        DataInput::open(Some(self.path.as_os_str()))
+    }
+
+    /// Read sequentially even when seeks are possible
+    pub fn stream(&self) -> Res<Input> {
+        Input::open(Some(self.path.as_os_str()))
+    }
+}
+
+impl Drop for Reopenable {
+    fn drop(&mut self) {
+        if self.temporary {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Buffered and compressed output
+pub struct Output(Encoder<BufWriter<Box<dyn Write>>>);
+
+impl Output {
+    pub fn create(path: Option<&OsStr>, codec: Codec, level: u32) -> Res<Self> {
+        let writer: Box<dyn Write> = if is_stdio(path) {
+            Box::new(io::stdout())
+        } else {
+            Box::new(File::create(path.unwrap())?)
+        };
+        Ok(Output(Encoder::new(BufWriter::new(writer), codec, level)?))
+    }
+
+    /// Without compression
+    pub fn plain(path: Option<&OsStr>) -> Res<Self> {
+        Self::create(path, Codec::None, 0)
+    }
+
+    /// Must be called, otherwise errors of writing the end are lost
+    pub fn finish(self) -> io::Result<()> {
+        self.0.finish()?.flush()
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
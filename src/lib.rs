@@ -1,5 +1,5 @@
 #![feature(entry_insert)]
-#![allow(clippy::blacklisted_name)]  // bar is good identifier
+#![allow(clippy::disallowed_names)]  // bar is good identifier
 #[macro_use] extern crate failure;
 use failure::Error;
 
@@ -9,6 +9,15 @@ pub mod data;
 pub mod parser;
 pub mod draw;
 pub mod search;
+pub mod shared;
+pub mod similar;
+pub mod stats;
+pub mod cooccur;
+pub mod score;
+pub mod bgzf;
+pub mod compress;
+pub mod io;
+pub mod verify;
 
 pub mod progress;
 
@@ -1,5 +1,5 @@
 #![feature(proc_macro_hygiene, decl_macro)]
-#![allow(clippy::blacklisted_name)]  // bar is good identifier
+#![allow(clippy::disallowed_names)]  // bar is good identifier
 #[macro_use] extern crate rocket;
 #[macro_use] extern crate clap;
 #[macro_use] extern crate failure;
@@ -7,7 +7,6 @@ extern crate pikadots;
 use std::ffi::OsStr;
 use std::fs::File;
 use pikadots::Res;
-use pikadots::progress::*;
 use std::io::{BufReader, Cursor};
 use {std::io, std::io::prelude::*};
 use std::path::PathBuf;
@@ -15,232 +14,506 @@ use streaming_iterator::StreamingIterator;
 use pikadots::data::SimpleData;
 use std::collections::HashMap;
 use pikadots::search::UserSelector;
+use pikadots::shared::{SharedFile, DataFile};
+use pikadots::compress::Codec;
+use pikadots::io::{Input, DataInput, Output, Reopenable};
+use pikadots::draw::{Palette, Chart};
 use parking_lot::Mutex;
 
 mod web;
 
-enum FileOrStdin {
-    File(File),
-    Stdin(std::io::Stdin)
-}
-
-impl FileOrStdin {
-    fn new(opt: Option<&OsStr>) -> Res<Self> {
-        Ok(match opt {
-            None => Self::Stdin(io::stdin()),
-            Some(s) => Self::File(File::open(s)?)
-        })
-    }
+fn do_parse(source: Input, mut dest: Output) -> Res<()> {
+    use pikadots::data::*;
+    use pikadots::parser::parse_json;
+    let mut data = Data::new(Cursor::new(Vec::new()));
+    eprintln!("Reading...");
+    let bar = source.bar.clone();
+    parse_json(&mut BufReader::new(source), &mut data)?;
+    bar.finish();
+    eprintln!("Writing {} entries...", data.cache.cached.len());
+    write_all(&data, &mut dest)?;
+    dest.finish()?;
+    Ok(())
 }
 
-enum FileOrStdout {
-    File(File),
-    Stdout(std::io::Stdout)
+/// Group of users drawn into a single image
+struct DrawJob {
+    users: Vec<UserSelector>,
+    file_name: String,
+    tz: i8,
+    palette: Palette,
+    chart: Chart,
+    /// Several kinds are overlaid
+    kinds: Vec<String>,
 }
 
-impl FileOrStdout {
-    fn new(opt: Option<&OsStr>) -> Res<Self> {
-        Ok(match opt {
-            None => Self::Stdout(io::stdout()),
-            Some(s) => Self::File(File::create(s)?)
+impl DrawJob {
+    fn new(users: &str, tz: i8, palette: Palette, chart: Chart, kinds: &[String]) -> Res<Self> {
+        let users: Res<Vec<_>> = users
+            .split(',')
+            .map(UserSelector::new)
+            .collect();
+        let users = users?;
+        Ok(DrawJob {
+            file_name: Self::file_name(&users, chart, kinds),
+            users,
+            tz,
+            palette,
+            chart,
+            kinds: kinds.to_vec()
         })
     }
-}
 
-fn do_parse(source: FileOrStdin, src_gz: bool, dest: FileOrStdout, dest_gz: bool) -> Res<()> {
-    use pikadots::data::*;
-    use pikadots::parser::parse_json;
-    let mut data = Data::new(Cursor::new(Vec::new()));
-    // FIXME: Too much boilerplate
-    eprintln!("Reading...");
-    match source {
-        FileOrStdin::File(f) => {
-            if src_gz {
-                parse_json(&mut BufReader::new(
-                    flate2::read::GzDecoder::new(
-                        pikadots::progress::ReaderWrapper::from_file(f)
-                )), &mut data)?;
-            } else {
-                parse_json(&mut BufReader::new(
-                    pikadots::progress::ReaderWrapper::from_file(f)
-                ), &mut data)?
-            }
-        },
-        FileOrStdin::Stdin(f) => {
-            if src_gz {
-                parse_json(&mut BufReader::new(flate2::read::GzDecoder::new(f)), &mut data)?;
-            } else {
-                parse_json(&mut BufReader::new(f), &mut data)?;
-            }
+    /// Different charts and kinds of the same users do not overwrite each other
+    fn file_name(users: &[UserSelector], chart: Chart, kinds: &[String]) -> String {
+        let mut name = pikadots::search::selector_name(users);
+        if kinds != [pikadots::data::COMMENTS] {
+            name = format!("{}.{}", name, kinds.join("+"));
+        }
+        match chart {
+            Chart::Dots => format!("{}.png", name),
+            Chart::WeekHour => format!("{}.weekhour.png", name),
+            Chart::Hourly => format!("{}.hourly.png", name),
         }
     }
-    eprintln!("Writing {} entries...", data.cached.len());
-    match dest {
-        FileOrStdout::File(f) => {
-            if dest_gz {
-                write_all(&data, flate2::write::GzEncoder::new(f, flate2::Compression::new(3)))?;
-            } else {
-                write_all(&data, f)?;
+
+    /// Parses line of users file: `selectors [file_name] [tz=N] [palette=NAME] [chart=NAME] [kind=KINDS]`.
+    /// Only known keys are options, so file name may contain `=`
+    fn from_line(line: &str, tz: i8, palette: Palette, chart: Chart, kinds: &[String]) -> Res<Self> {
+        let mut parts = line.split_whitespace();
+        let mut job = Self::new(parts.next().unwrap_or_default(), tz, palette, chart, kinds)?;
+        let mut file_name = None;
+        for part in parts {
+            match part.split_once('=') {
+                Some(("tz", tz)) => job.tz = tz.parse()?,
+                Some(("palette", palette)) => job.palette = palette.parse()?,
+                Some(("chart", chart)) => job.chart = chart.parse()?,
+                Some(("kind", kinds)) => job.kinds = pikadots::data::parse_kinds(kinds)?,
+                _ if file_name.is_some() => return Err(format_err!("Unknown option or second file name: {}", part)),
+                _ => file_name = Some(part.to_string()),
             }
         }
-        FileOrStdout::Stdout(f) => {
-            if dest_gz {
-                write_all(&data, flate2::write::GzEncoder::new(f, flate2::Compression::new(3)))?;
-            } else {
-                write_all(&data, f)?;
-            }
+        job.file_name = file_name.unwrap_or_else(|| Self::file_name(&job.users, job.chart, &job.kinds));
+        Ok(job)
+    }
+}
+
+fn read_jobs(path: &OsStr, tz: i8, palette: Palette, chart: Chart, kinds: &[String]) -> Res<Vec<DrawJob>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut jobs = Vec::new();
+    for (n, ln) in reader.lines().enumerate() {
+        let ln = ln?;
+        let ln = ln.trim();
+        if ln.is_empty() || ln.starts_with('#') {
+            continue;
         }
+        let job = DrawJob::from_line(ln, tz, palette, chart, kinds)
+            .map_err(|e| format_err!("{:?}:{}: {}", path, n + 1, e))?;
+        jobs.push(job);
     }
-    Ok(())
+    Ok(jobs)
 }
 
-fn do_draw(gzip: bool, data: FileOrStdin, output: PathBuf, users: Vec<Vec<UserSelector>>, index: Option<File>, tz: i8) -> Res<()> {
+fn do_draw(data: DataInput, output: PathBuf, jobs: Vec<DrawJob>, index: Option<File>, threads: usize) -> Res<()> {
     use pikadots::data::*;
     use pikadots::search::*;
 
-    fn work<F>(mut searcher: F, output: PathBuf, users: Vec<Vec<UserSelector>>, tz: i8) -> Res<()>
+    // All groups are searched at once, so data is read only once
+    fn work<F>(mut searcher: F, output: PathBuf, jobs: Vec<DrawJob>) -> Res<()>
         where F: FnMut(Vec<Vec<UserSelector>>) -> Res<Vec<Vec<UserInfo>>>
     {
-        let names: Vec<String> = users.iter().map(|x| selector_name(&x[..])).collect();
+        let users = jobs.iter().map(|x| x.users.clone()).collect();
         let found = searcher(users)?;
-        for (i, group) in found.into_iter().enumerate() {
-            let name = &names[i];
-            let comments = group.into_iter().map(|x| x.comments);
-            let sorted = pikadots::join_sorted(comments);
-            let gen = pikadots::draw::generate(&sorted);
-            let img = gen.into_image(tz)?;
-            let output = output.join(format!("{}.png", name));
+        for (job, group) in jobs.iter().zip(found) {
+            let layers: Vec<_> = job.kinds.iter()
+                .map(|kind| pikadots::join_sorted(group.iter().map(|x| x.events(kind).iter().cloned())))
+                .collect();
+            let img = pikadots::draw::draw_layers(&layers, job.chart, job.tz, job.palette)?;
+            let output = output.join(&job.file_name);
             image::DynamicImage::ImageRgb8(img).save_with_format(output, image::PNG)?;
         }
         Ok(())
     }
 
-    match data {
-        FileOrStdin::File(f) => {
-            // FIXME: Incorrect progress. Should be Wrapper<BufReader<File>> instead of BufReader<Wrapper<File>>
-            // TODO: Make correct benchamarks of reading data. It looks really slow
-            let reader = ReaderWrapper::from_file(f);
-            let bar = reader.bar.clone();
-            let reader = BufReader::new(reader);
-
-            let res = if gzip {
-                let mut data: Data<_, CacheRef> = Data::new(flate2::read::GzDecoder::new(reader));
-                work(|x| find(&mut data, &x, SearchSettings {
-                    use_cache: false,
-                    limit: std::usize::MAX
-                }), output, users, tz)
-            } else {
-                let mut data: Data<_, SeekableRef> = Data::new(Mutex::new(reader));
-                let use_cache = if let Some(idx) = index {
-                    load_index(idx, &mut data)?;
-                    true
-                } else {
-                    false
-                };
-                work(|x| find_seek(&mut data, x, SearchSettings {
-                    use_cache,
-                    limit: std::usize::MAX
-                }), output, users, tz)
-            };
-            bar.finish();
-            res
+    // TODO: Make correct benchamarks of reading data. It looks really slow
+    let bar = data.bar();
+    let res = match data {
+        DataInput::Stream(reader) => {
+            let mut data: Data<_, CacheRef> = Data::new(reader);
+            work(|x| find(&mut data, &x, SearchSettings {
+                use_cache: false,
+                limit: usize::MAX,
+                threads
+            }), output, jobs)
         }
-        FileOrStdin::Stdin(std) => {
-            let reader = ReaderWrapper::new(std, 0);
-            let bar = reader.bar.clone();
-            let res = if gzip {
-                let mut data: Data<_, CacheRef> = Data::new(flate2::read::GzDecoder::new(reader));
-                work(|x| find(&mut data, &x, SearchSettings {
-                    use_cache: false,
-                    limit: std::usize::MAX
-                }), output, users, tz)
+        data => {
+            let mut data: Data<_, SeekableRef> = Data::new(Mutex::new(data.seekable()?));
+            let use_cache = if let Some(idx) = index {
+                load_index(idx, &mut data)?;
+                true
             } else {
-                let mut data: Data<_, CacheRef> = Data::new(reader);
-                work(|x| find(&mut data, &x, SearchSettings {
-                    use_cache: false,
-                    limit: std::usize::MAX
-                }), output, users, tz)
+                false
             };
-            bar.finish();
-            res
+            work(|x| {
+                let settings = SearchSettings {
+                    use_cache,
+                    limit: usize::MAX,
+                    threads
+                };
+                eprintln!("Search plan: {}", plan_seek(&data, &x, &settings));
+                find_seek(&mut data, x, settings)
+            }, output, jobs)
         }
-    }
+    };
+    bar.finish();
+    res
 }
 
-fn do_index(file: File, out: FileOrStdout) -> Res<()> {
+fn do_index(data: DataInput, mut out: Output) -> Res<()> {
     use pikadots::data::*;
-    let reader = ReaderWrapper::from_file(file);
-    let bar = reader.bar.clone();
-    let reader = Mutex::new(BufReader::new(reader));
+    let bar = data.bar();
+    let reader = Mutex::new(data.seekable()?);
 
     let mut data: Data<_, SeekableRef> = Data::new(reader);
     let mut reader = data.get_reader(ReadConfig::None)?;
-    let make_ln = |i: &UserInfo| {
-        if let Some(s) = i.seek {
-            format!("{},{},{}\n", i.pikabu_id, s, i.name)
-        } else {
-            format!("{},,{}\n", i.pikabu_id, i.name)
+    // One line per name, current name goes first. Lines with former names repeat id and seek
+    while let Some(i) = reader.next() {
+        let seek = i.seek.map(|s| s.to_string()).unwrap_or_default();
+        for name in i.names() {
+            writeln!(out, "{},{},{}", i.pikabu_id, seek, name)?;
         }
+    }
+    reader.finish()?;
+    out.finish()?;
+    bar.finish();
+    Ok(())
+}
+
+fn do_verify(data: DataInput, index: Option<File>, mut repair: Option<Output>) -> Res<()> {
+    use pikadots::data::read_index;
+    use pikadots::verify::verify;
+    let index = match index {
+        Some(f) => Some(read_index(BufReader::new(f))?),
+        None => None
     };
-    match out {
-        FileOrStdout::File(mut f) => {
-            while let Some(i) = reader.next() {
-                f.write_all(make_ln(i).as_bytes())?;
-            }
+    let bar = data.bar();
+    let mut reader = data.seekable()?;
+    let report = verify(&mut reader, index.as_deref(), repair.as_mut())?;
+    bar.finish();
+    if let Some(x) = repair {
+        x.finish()?;
+    }
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    for i in &report.problems {
+        writeln!(stdout, "{}", i)?;
+    }
+    eprintln!("{} records, {} skipped, {} problems", report.records, report.skipped, report.problems.len());
+    if !report.problems.is_empty() {
+        return Err(format_err!("Data has problems"));
+    }
+    Ok(())
+}
+
+type SeekData = pikadots::data::Data<Mutex<pikadots::progress::ReaderWrapper<DataFile<pikadots::progress::ReaderWrapper<SharedFile>>>>, pikadots::data::SeekableRef>;
+
+/// Users matching any of selectors. Index is used when data is seekable,
+/// then the data is returned to be read again. Streams are consumed
+fn select_users(data: DataInput, index: Option<File>, users: Vec<UserSelector>, threads: usize) -> Res<(Vec<pikadots::data::UserInfo>, Option<SeekData>)> {
+    use pikadots::data::*;
+    use pikadots::search::*;
+    let settings = |use_cache| SearchSettings { use_cache, limit: usize::MAX, threads };
+    let (mut found, data) = match data {
+        DataInput::Stream(reader) => {
+            let mut data: Data<_, CacheRef> = Data::new(reader);
+            (find(&mut data, &[users], settings(false))?, None)
         }
-        FileOrStdout::Stdout(mut std) => {
-            while let Some(i) = reader.next() {
-                std.write_all(make_ln(i).as_bytes())?;
-            }
+        data => {
+            let mut data: SeekData = Data::new(Mutex::new(data.seekable()?));
+            let use_cache = if let Some(idx) = index {
+                load_index(idx, &mut data)?;
+                true
+            } else {
+                false
+            };
+            (find_seek(&mut data, vec![users], settings(use_cache))?, Some(data))
         }
+    };
+    let found = found.pop().unwrap_or_default();
+    if found.is_empty() {
+        return Err(format_err!("No such user"));
     }
+    Ok((found, data))
+}
+
+fn do_similar(source: Reopenable, index: Option<File>, users: Vec<UserSelector>, settings: pikadots::similar::SimilarSettings, threads: usize) -> Res<()> {
+    use pikadots::data::*;
+    use pikadots::similar::find_similar;
+    let data = source.open()?;
+    let mut bar = data.bar();
+    let (target, data) = select_users(data, index, users, threads)?;
+    let found = match data {
+        Some(mut data) => find_similar(&mut data, &target, settings)?,
+        None => {
+            // Stream is consumed by the search, everyone is compared during the second reading
+            bar.finish();
+            let reader = source.stream()?;
+            bar = reader.bar.clone();
+            find_similar(&mut Data::<_, CacheRef>::new(reader), &target, settings)?
+        }
+    };
     bar.finish();
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    writeln!(stdout, "score\tcorrelation\tcosine\toverlap\tid\tname\tcomments")?;
+    for i in found {
+        writeln!(
+            stdout, "{:.4}\t{:.4}\t{:.4}\t{:.4}\t{}\t{}\t{}",
+            i.score, i.correlation, i.cosine, i.overlap,
+            i.user.pikabu_id, i.user.name, i.user.comments.len()
+        )?;
+    }
+    Ok(())
+}
+
+fn do_cooccur(source: Reopenable, index: Option<File>, users: Vec<UserSelector>, settings: pikadots::cooccur::CooccurSettings, threads: usize) -> Res<()> {
+    use pikadots::data::*;
+    use pikadots::cooccur::find_cooccur;
+    let data = source.open()?;
+    let mut bar = data.bar();
+    let (target, data) = select_users(data, index, users, threads)?;
+    let found = match data {
+        Some(mut data) => find_cooccur(&mut data, &target, settings)?,
+        None => {
+            // Stream is consumed by the search, everyone is compared during the second reading
+            bar.finish();
+            let reader = source.stream()?;
+            bar = reader.bar.clone();
+            find_cooccur(&mut Data::<_, CacheRef>::new(reader), &target, settings)?
+        }
+    };
+    bar.finish();
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    writeln!(stdout, "count\tlift\tstories\tevents\tid\tname")?;
+    for i in found {
+        writeln!(
+            stdout, "{}\t{:.4}\t{}\t{}\t{}\t{}",
+            i.count, i.lift, i.stories, i.events, i.pikabu_id, i.name
+        )?;
+    }
+    Ok(())
+}
+
+fn do_stats(data: DataInput, index: Option<File>, users: Vec<UserSelector>, tz: i8, json: bool, threads: usize) -> Res<()> {
+    use pikadots::stats::{UserStats, format_seconds};
+    let bar = data.bar();
+    let (found, _) = select_users(data, index, users, threads)?;
+    bar.finish();
+    let points = pikadots::join_sorted(found.into_iter().map(|x| x.comments));
+    let stats = UserStats::new(&points, tz);
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    if json {
+        serde_json::to_writer_pretty(&mut stdout, &stats)?;
+        writeln!(stdout)?;
+        return Ok(());
+    }
+    let or_none = |x: Option<String>| x.unwrap_or_else(|| "-".to_string());
+    writeln!(stdout, "Comments: {}", stats.comments)?;
+    writeln!(stdout, "First: {}", or_none(stats.first.map(|x| x.to_string())))?;
+    writeln!(stdout, "Last: {}", or_none(stats.last.map(|x| x.to_string())))?;
+    writeln!(stdout, "Active days: {}", stats.active_days)?;
+    writeln!(stdout, "Longest streak: {}", or_none(stats.longest_streak.map(|x| format!("{} days, {} - {}", x.days, x.start, x.end))))?;
+    writeln!(stdout, "Longest gap: {}", or_none(stats.longest_gap.map(|x| format!("{}, {} - {}", format_seconds(x.seconds), x.start, x.end))))?;
+    writeln!(stdout, "Median interval: {}", or_none(stats.median_interval.map(format_seconds)))?;
+    writeln!(stdout, "Peak minute: {} comments, {} times", stats.peak_minute, stats.peak_minutes)?;
+    writeln!(stdout, "Weekdays:")?;
+    for (day, cnt) in ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"].iter().zip(&stats.weekdays) {
+        writeln!(stdout, "\t{}\t{}", day, cnt)?;
+    }
+    writeln!(stdout, "Hours:")?;
+    for (hour, cnt) in stats.hours.iter().enumerate() {
+        writeln!(stdout, "\t{:02}:00\t{}", hour, cnt)?;
+    }
+    Ok(())
+}
+
+fn do_score(data: Input, settings: pikadots::score::ScoreSettings, json: bool, draw: Option<PathBuf>, tz: i8) -> Res<()> {
+    use pikadots::data::*;
+    use pikadots::score::find_bots;
+    let bar = data.bar.clone();
+    // Everything is read sequentially, so any compression and stdin work
+    let mut data: Data<_, CacheRef> = Data::new(data);
+    let found = find_bots(&mut data, settings)?;
+    bar.finish();
+
+    if let Some(dir) = draw {
+        for (_, user) in &found {
+            let img = pikadots::draw::generate(&user.comments).into_image(tz, Palette::default())?;
+            image::DynamicImage::ImageRgb8(img).save_with_format(dir.join(format!("{}.png", user.name)), image::PNG)?;
+        }
+    }
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let scores: Vec<_> = found.into_iter().map(|x| x.0).collect();
+    if json {
+        serde_json::to_writer_pretty(&mut stdout, &scores)?;
+        writeln!(stdout)?;
+        return Ok(());
+    }
+    writeln!(stdout, "score,sleep_gap,periodicity,period,burstiness,same_second,comments,id,name")?;
+    for i in scores {
+        writeln!(
+            stdout, "{:.4},{},{:.4},{},{:.4},{:.4},{},{},{}",
+            i.score, i.sleep_gap, i.periodicity, i.period, i.burstiness, i.same_second,
+            i.comments, i.pikabu_id, i.name
+        )?;
+    }
     Ok(())
 }
 
 fn load_index<R>(idx: File, data: &mut pikadots::data::Data<R, pikadots::data::SeekableRef>) -> Res<()> {
-    let reader = BufReader::new(idx);
     let mut names = HashMap::new();
     let mut ids = HashMap::new();
-    for ln in reader.lines() {
-        let ln = ln?;
-        let splitted: Vec<&str> = ln.splitn(3, ',').collect();
-        if splitted.len() != 3 {
-            continue;
-        }
-        let (pik, sk, name) = (splitted[0], splitted[1], splitted[2]);
-        let (pik, sk, name) = (pik.parse()?, sk.parse()?, name.to_lowercase());
-        names.insert(name, pikadots::data::SeekableRef::Seek(sk));
-        ids.insert(pik, pikadots::data::SeekableRef::Seek(sk));
+    let mut last_pik = None;
+    for entry in pikadots::data::read_index(BufReader::new(idx))? {
+        let (pik, name) = (entry.pikabu_id, entry.name.to_lowercase());
+        let r = pikadots::data::SeekableRef::Seek(entry.seek);
+        // Former names of the same user follow the current one
+        pikadots::data::insert_name(&mut names, name, r, last_pik != Some(pik));
+        ids.insert(pik, r);
+        last_pik = Some(pik);
     }
     data.fill_cache(names, ids);
     Ok(())
 }
 
+/// Files served by web server. Loaded again on every reload
+struct ServeSource {
+    data: PathBuf,
+    index: Option<PathBuf>,
+    mem: bool,
+    cache: pikadots::data::CacheConfig,
+}
+
+impl ServeSource {
+    fn load(&self) -> Res<(web::Data, bool)> {
+        let data = SharedFile::open(&self.data)?;
+        let data = Mutex::new(DataFile::new(data)?);
+        let mut data = web::Data::new(data);
+        let mut use_cache = false;
+        if let Some(x) = &self.index {
+            load_index(File::open(x)?, &mut data)?;
+            use_cache = true;
+        }
+        if self.mem {
+            // Read all
+            let mut reader = data.get_reader(pikadots::data::ReadConfig::Cache(self.cache))?;
+            while reader.next().is_some() {
+                // Just reading all items
+            }
+            reader.finish()?;
+            use_cache = true;
+        }
+        Ok((data, use_cache))
+    }
+
+    fn files(&self) -> Vec<PathBuf> {
+        std::iter::once(self.data.clone()).chain(self.index.clone()).collect()
+    }
+}
+
+fn jobs(sub: &clap::ArgMatches) -> Res<usize> {
+    Ok(match sub.value_of("jobs") {
+        Some(x) => x.parse()?,
+        None => std::thread::available_parallelism().map(|x| x.get()).unwrap_or(1)
+    })
+}
+
 fn main() -> Res<()> {
     let app = clap_app!(PikaDots =>
         (version: "0.2")
         (author: "by Dino")
+        (@arg progress: --progress +takes_value +global "Progress: bar (default), json lines on stderr or none")
         (@subcommand draw =>
             (about: "Draw images")
-            (@arg tz: -t --tz "Timezone")
-            (@arg gzip: -z --gzip "Use gzip when reading data")
-            (@arg data: -d --data +takes_value "Path to data. Omit to read from stdin")
+            (@arg tz: -t --tz +takes_value +allow_hyphen_values "Timezone")
+            (@arg palette: -p --palette +takes_value "Palette: normal, comments or posts")
+            (@arg chart: -c --chart +takes_value "Chart: dots (default), weekhour or hourly")
+            (@arg kind: -k --kind +takes_value
+                "Kinds of events, comments by default. Several kinds are overlaid: green, red and blue")
+            (@arg jobs: -j --jobs +takes_value "Number of threads used for full scan")
+            (@arg data: -d --data +takes_value "Path to data, compression is detected. Omit or `-` to read from stdin")
             (@arg index: -i --index +takes_value "Load index from file")
             (@arg output: -o --output +takes_value * "Output path")
-            (@arg users: -u --users ... +takes_value * "User selectors")
+            (@arg users: -u --users ... +takes_value "User selectors")
+            (@arg users_file: -f --("users-file") +takes_value
+                "File with one group of selectors per line: `selectors [file_name] [tz=N] [palette=NAME] [chart=NAME] [kind=KINDS]`")
         )
         (@subcommand parse =>
             (about: "Parse json")
-            (@arg source: -s --src +takes_value "Path to json. Omit to read from stdin")
-            (@arg gzip_in: -g "Use gzip for input")
-            (@arg dest: -d --dest +takes_value "Path to data. Omit to write to stdout")
-            (@arg gzip_out: -z "Use gzip for output")
+            (@arg source: -s --src +takes_value "Path to json, compression is detected. Omit or `-` to read from stdin")
+            (@arg dest: -d --dest +takes_value "Path to data. Omit or `-` to write to stdout")
+            (@arg compress: -c --compress +takes_value
+                "Compression of data: none (default), gzip, bgzf, zstd or xz. Only none and bgzf can be indexed and searched")
+            (@arg level: -l --level +takes_value "Compression level, 3 for gzip, bgzf and zstd and 6 for xz by default")
         )
         (@subcommand index =>
             (about: "Create indexes")
             (@arg data: -d --data * +takes_value "Path to data")
-            (@arg output: -o --output +takes_value "Output file (csv). Omit to write to stdout")
+            (@arg output: -o --output +takes_value "Output file (csv). Omit or `-` to write to stdout")
+        )
+        (@subcommand verify =>
+            (about: "Check every record of data and offsets of index")
+            (alias: "fsck")
+            (@arg data: -d --data * +takes_value "Path to data")
+            (@arg index: -i --index +takes_value "Check offsets of this index too")
+            (@arg repair: -r --repair +takes_value "Write data without broken records to this file")
+            (@arg compress: -c --compress +takes_value "Compression of repaired data: none (default), gzip, bgzf, zstd or xz")
+            (@arg level: -l --level +takes_value "Compression level")
+        )
+        (@subcommand similar =>
+            (about: "Find users with similar activity")
+            (@arg data: -d --data * +takes_value "Path to data, compression is detected. `-` to read from stdin")
+            (@arg index: -i --index +takes_value "Load index from file")
+            (@arg users: -u --users * +takes_value "User selectors, compared as one user")
+            (@arg top: -k --top +takes_value "Number of users to show (default: 20)")
+            (@arg min: -m --min +takes_value "Skip users with less comments (default: 10)")
+            (@arg jobs: -j --jobs +takes_value "Number of threads used for full scan")
+        )
+        (@subcommand cooccur =>
+            (about: "Find users writing in the same stories at the same time")
+            (@arg data: -d --data * +takes_value "Path to data, compression is detected. `-` to read from stdin")
+            (@arg index: -i --index +takes_value "Load index from file")
+            (@arg users: -u --users * +takes_value "User selectors, compared as one user")
+            (@arg window: -w --window +takes_value "Maximum distance between events, in minutes (default: 10)")
+            (@arg top: -k --top +takes_value "Number of users to show (default: 20)")
+            (@arg min: -m --min +takes_value "Skip users with less close events (default: 2)")
+            (@arg jobs: -j --jobs +takes_value "Number of threads used for full scan")
+        )
+        (@subcommand stats =>
+            (about: "Show activity statistics")
+            (@arg data: -d --data * +takes_value "Path to data, compression is detected. `-` to read from stdin")
+            (@arg index: -i --index +takes_value "Load index from file")
+            (@arg users: -u --users * +takes_value "User selectors, compared as one user")
+            (@arg tz: -t --tz +takes_value +allow_hyphen_values "Timezone")
+            (@arg json: --json "Print as json")
+            (@arg jobs: -j --jobs +takes_value "Number of threads used for full scan")
+        )
+        (@subcommand score =>
+            (about: "Rank all users by how automated their activity looks")
+            (@arg data: -d --data * +takes_value "Path to data, compression is detected. `-` to read from stdin")
+            (@arg top: -k --top +takes_value "Number of users to show (default: 100)")
+            (@arg min: -m --min +takes_value "Skip users with less comments (default: 100)")
+            (@arg tz: -t --tz +takes_value +allow_hyphen_values "Timezone of drawn images")
+            (@arg json: --json "Print as json instead of csv")
+            (@arg draw: --draw +takes_value "Draw found users into this directory")
         )
         (@subcommand serve =>
             (about: "Start webserver")
@@ -248,6 +521,20 @@ fn main() -> Res<()> {
             (@arg index: -i --index +takes_value "Load index from file")
             (@arg mem: -m --memory "Load everything into memory")
             (@arg seeks: -s --seeks "Store seeks in memory instead of values")
+            (@arg jobs: -j --jobs +takes_value "Number of threads used for full scan")
+            (@arg image_cache: --("image-cache") +takes_value "Size of rendered images cache in megabytes (default: 64)")
+            (@arg base: --base +takes_value "Path to mount server at (default: /)")
+            (@arg profile_link: --("profile-link") +takes_value "External profile link, {id} and {name} are replaced. Empty to disable")
+            (@arg cors: --cors +takes_value +multiple "Origins allowed to use API from browser, * for any")
+            (@arg address: --address +takes_value "Address to bind, overrides Rocket.toml")
+            (@arg port: --port +takes_value "Port to bind, overrides Rocket.toml")
+            (@arg reload_token: --("reload-token") +takes_value "Enables POST /reload with this bearer token")
+            (@arg watch: --watch +takes_value "Reload when data or index changes, checking every N seconds")
+            (@arg rate: --rate +takes_value "Query cost restored per second for every client. Index lookup costs 1, full scan 100")
+            (@arg burst: --burst +takes_value "Query cost client can spend at once (default: 1000)")
+            (@arg max_cost: --("max-cost") +takes_value "Reject queries costing more")
+            (@arg max_scans: --("max-scans") +takes_value "Number of full scans allowed to run at the same time")
+            (@arg trust_proxy: --("trust-proxy") "Take client address from X-Real-IP. Use only behind reverse proxy setting it")
             (@group map_name =>
                 (@arg name: --name "Create only name hashtable (default)")
                 (@arg no_name: --no_name "Do not create name hashtable")
@@ -260,79 +547,198 @@ fn main() -> Res<()> {
     );
 
     let matches = app.get_matches();
+    if let Some(x) = matches.value_of("progress") {
+        pikadots::progress::set_mode(x.parse()?);
+    }
     match matches.subcommand() {
         ("draw", sub) => {
             let sub = sub.unwrap();
             let tz = sub.value_of("tz")
                 .map(|x| x.parse())
                 .unwrap_or_else(|| Ok(0))?;
-            let gzip = sub.is_present("gzip");
-            let data = FileOrStdin::new(sub.value_of_os("data"))?;
+            let palette = sub.value_of("palette")
+                .map(|x| x.parse())
+                .unwrap_or_else(|| Ok(Palette::default()))?;
+            let chart = sub.value_of("chart")
+                .map(|x| x.parse())
+                .unwrap_or_else(|| Ok(Chart::default()))?;
+            let kinds = pikadots::data::parse_kinds(sub.value_of("kind").unwrap_or(pikadots::data::COMMENTS))?;
+            let data = DataInput::open(sub.value_of_os("data"))?;
             let output = PathBuf::from(sub.value_of_os("output").unwrap());
             let index = sub.value_of_os("index").map(File::open);
             let index = if let Some(idx) = index {Some(idx?)} else {None};
 
-            let vals = sub.values_of_os("users").unwrap();
-            let mut users = Vec::with_capacity(vals.len());
-            for i in vals {
+            let mut draw_jobs = Vec::new();
+            for i in sub.values_of_os("users").into_iter().flatten() {
                 let s = i.to_str().ok_or_else(|| format_err!("Invalid OsStr: {:?}", i))?;
-                let splitted: Res<Vec<_>> = s
-                    .split(',')
-                    .map(UserSelector::new)
-                    .collect();
-                users.push(splitted?)
+                draw_jobs.push(DrawJob::new(s, tz, palette, chart, &kinds)?);
+            }
+            if let Some(path) = sub.value_of_os("users_file") {
+                draw_jobs.append(&mut read_jobs(path, tz, palette, chart, &kinds)?);
+            }
+            if draw_jobs.is_empty() {
+                return Err(format_err!("Nothing to draw. Specify --users or --users-file"));
             }
-            do_draw(gzip, data, output, users, index, tz)
+            do_draw(data, output, draw_jobs, index, jobs(sub)?)
         },
         ("parse", sub) => {
             let sub = sub.unwrap();
-            let source = FileOrStdin::new(sub.value_of_os("source"))?;
-            let src_gz = sub.is_present("gzip_in");
-            let dest = FileOrStdout::new(sub.value_of_os("dest"))?;
-            let dest_gz = sub.is_present("gzip_out");
-            do_parse(source, src_gz, dest, dest_gz)
+            let codec = sub.value_of("compress")
+                .map(|x| x.parse())
+                .unwrap_or_else(|| Ok(Codec::default()))?;
+            let level = codec.level(sub.value_of("level").map(|x| x.parse()).transpose()?)?;
+            let source = Input::open(sub.value_of_os("source"))?;
+            let dest = Output::create(sub.value_of_os("dest"), codec, level)?;
+            do_parse(source, dest)
         },
         ("index", sub) => {
             let sub = sub.unwrap();
-            let data = File::open(sub.value_of_os("data").unwrap())?;
-            let output = FileOrStdout::new(sub.value_of_os("output"))?;
+            let data = DataInput::open(sub.value_of_os("data"))?;
+            let output = Output::plain(sub.value_of_os("output"))?;
             do_index(data, output)
         },
-        ("serve", sub) => {
+        ("verify", sub) => {
             let sub = sub.unwrap();
-            let data = File::open(sub.value_of_os("data").unwrap())?;
+            let data = DataInput::open(sub.value_of_os("data"))?;
             let index = sub.value_of_os("index").map(File::open);
-            let mem = sub.is_present("mem");
-            let seeks = sub.is_present("seeks");
-            let name = !sub.is_present("no_name");
-            let id = !sub.is_present("no_id");
-
-            let data = Mutex::new(BufReader::new(data));
-            let mut data = web::Data::new(data);
-            let mut use_cache = false;
-            if let Some(x) = index {
-                load_index(x?, &mut data)?;
-                use_cache = true;
-            }
-            if mem {
-                // Read all
-                let mut reader = data.get_reader(
-                    pikadots::data::ReadConfig::Cache(pikadots::data::CacheConfig {
-                        names: name,
-                        ids: id,
-                        offsets: false,
-                        prefer_seek: seeks
-                    })
-                )?;
-                while let Some(_) = reader.next() {
-                    // Just reading all items
+            let index = if let Some(idx) = index {Some(idx?)} else {None};
+            let codec = sub.value_of("compress")
+                .map(|x| x.parse())
+                .unwrap_or_else(|| Ok(Codec::default()))?;
+            let level = codec.level(sub.value_of("level").map(|x| x.parse()).transpose()?)?;
+            let repair = match sub.value_of_os("repair") {
+                Some(x) => Some(Output::create(Some(x), codec, level)?),
+                None => None
+            };
+            do_verify(data, index, repair)
+        },
+        ("similar", sub) => {
+            let sub = sub.unwrap();
+            let data = Reopenable::new(sub.value_of_os("data"))?;
+            let index = sub.value_of_os("index").map(File::open);
+            let index = if let Some(idx) = index {Some(idx?)} else {None};
+            let users: Res<Vec<_>> = sub.value_of("users").unwrap()
+                .split(',')
+                .map(UserSelector::new)
+                .collect();
+            let settings = pikadots::similar::SimilarSettings {
+                top: sub.value_of("top").map(|x| x.parse()).unwrap_or(Ok(20))?,
+                min_comments: sub.value_of("min").map(|x| x.parse()).unwrap_or(Ok(10))?,
+            };
+            do_similar(data, index, users?, settings, jobs(sub)?)
+        },
+        ("cooccur", sub) => {
+            let sub = sub.unwrap();
+            let data = Reopenable::new(sub.value_of_os("data"))?;
+            let index = sub.value_of_os("index").map(File::open);
+            let index = if let Some(idx) = index {Some(idx?)} else {None};
+            let users: Res<Vec<_>> = sub.value_of("users").unwrap()
+                .split(',')
+                .map(UserSelector::new)
+                .collect();
+            let settings = pikadots::cooccur::CooccurSettings {
+                top: sub.value_of("top").map(|x| x.parse()).unwrap_or(Ok(20))?,
+                window: chrono::Duration::minutes(sub.value_of("window").map(|x| x.parse()).unwrap_or(Ok(10))?),
+                min_count: sub.value_of("min").map(|x| x.parse()).unwrap_or(Ok(2))?,
+            };
+            do_cooccur(data, index, users?, settings, jobs(sub)?)
+        },
+        ("stats", sub) => {
+            let sub = sub.unwrap();
+            let data = DataInput::open(sub.value_of_os("data"))?;
+            let index = sub.value_of_os("index").map(File::open);
+            let index = if let Some(idx) = index {Some(idx?)} else {None};
+            let users: Res<Vec<_>> = sub.value_of("users").unwrap()
+                .split(',')
+                .map(UserSelector::new)
+                .collect();
+            let tz = sub.value_of("tz").map(|x| x.parse()).unwrap_or(Ok(0))?;
+            do_stats(data, index, users?, tz, sub.is_present("json"), jobs(sub)?)
+        },
+        ("score", sub) => {
+            let sub = sub.unwrap();
+            let data = Input::open(sub.value_of_os("data"))?;
+            let settings = pikadots::score::ScoreSettings {
+                top: sub.value_of("top").map(|x| x.parse()).unwrap_or(Ok(100))?,
+                min_comments: sub.value_of("min").map(|x| x.parse()).unwrap_or(Ok(100))?,
+            };
+            let tz = sub.value_of("tz").map(|x| x.parse()).unwrap_or(Ok(0))?;
+            do_score(data, settings, sub.is_present("json"), sub.value_of_os("draw").map(PathBuf::from), tz)
+        },
+        ("serve", sub) => {
+            let sub = sub.unwrap();
+            let source = ServeSource {
+                data: PathBuf::from(sub.value_of_os("data").unwrap()),
+                index: sub.value_of_os("index").map(PathBuf::from),
+                mem: sub.is_present("mem"),
+                cache: pikadots::data::CacheConfig {
+                    names: !sub.is_present("no_name"),
+                    ids: !sub.is_present("no_id"),
+                    offsets: false,
+                    prefer_seek: sub.is_present("seeks")
                 }
-                use_cache = true;
-            }
+            };
+            let image_cache: usize = sub.value_of("image_cache").map(|x| x.parse()).unwrap_or(Ok(64))?;
+            let watch_interval = match sub.value_of("watch") {
+                Some(x) => Some(std::time::Duration::from_secs(x.parse()?)),
+                None => None
+            };
+            let settings = web::Settings {
+                threads: jobs(sub)?,
+                image_cache: image_cache * 1024 * 1024,
+                base: sub.value_of("base").unwrap_or("/").to_string(),
+                profile_link: match sub.value_of("profile_link") {
+                    Some("") => None,
+                    Some(x) => Some(x.to_string()),
+                    None => Some(web::DEFAULT_PROFILE_LINK.to_string())
+                },
+                cors: sub.values_of("cors").map(|x| x.map(str::to_string).collect()).unwrap_or_default(),
+                reload_token: sub.value_of("reload_token").map(str::to_string),
+                watch: source.files(),
+                watch_interval,
+                limits: web::Limits {
+                    rate: sub.value_of("rate").map(|x| x.parse()).transpose()?,
+                    burst: sub.value_of("burst").map(|x| x.parse()).transpose()?,
+                    max_cost: sub.value_of("max_cost").map(|x| x.parse()).transpose()?,
+                    max_scans: sub.value_of("max_scans").map(|x| x.parse()).transpose()?,
+                    trust_proxy: sub.is_present("trust_proxy"),
+                }
+            };
 
-            web::launch(data, use_cache, "/");
+            // Rocket 0.4 can't read Rocket.toml and then change it, but environment overrides it
+            if let Some(x) = sub.value_of("address") {
+                std::env::set_var("ROCKET_ADDRESS", x);
+            }
+            if let Some(x) = sub.value_of("port") {
+                x.parse::<u16>()?;
+                std::env::set_var("ROCKET_PORT", x);
+            }
+            web::launch(Box::new(move || source.load()), settings)?;
             Ok(())
         },
         _ => panic!("Unknown subcommand")
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(line: &str) -> Res<DrawJob> {
+        DrawJob::from_line(line, 3, Palette::default(), Chart::default(), &[pikadots::data::COMMENTS.to_string()])
+    }
+
+    #[test]
+    fn job_options_and_file_name() {
+        let x = job("user1 tz=5 chart=hourly").unwrap();
+        assert_eq!((x.tz, x.chart, x.file_name.as_str()), (5, Chart::Hourly, "user1.hourly.png"));
+
+        let x = job("user1 a=b.png tz=5").unwrap();
+        assert_eq!((x.tz, x.file_name.as_str()), (5, "a=b.png"));
+        // Known key is always an option
+        assert!(job("user1 tz=x=1.png").is_err());
+
+        assert!(job("user1 a.png b.png").is_err());
+        assert!(job("user1 tz=x").is_err());
+    }
+}
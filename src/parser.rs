@@ -10,6 +10,28 @@ struct Row {
     created_at_timestamp: i64,
     author_id: i64,
     author_username: String,
+    /// Kind of event, comments when missing
+    #[serde(rename = "type", default)]
+    kind: Option<String>,
+    /// Id of the event itself. Dumps name it differently, and some have several of the names,
+    /// so they are separate fields instead of aliases. See `Row::event_id`
+    #[serde(default)]
+    id: Option<i64>,
+    #[serde(default)]
+    comment_id: Option<i64>,
+    #[serde(default)]
+    post_id: Option<i64>,
+    #[serde(default)]
+    story_id: Option<i64>,
+    #[serde(default)]
+    rating: Option<i32>,
+}
+
+impl Row {
+    /// Plain `id` wins. Rows of comments may mention the post too, so its id is the last resort
+    fn event_id(&self) -> Option<i64> {
+        self.id.or(self.comment_id).or(self.post_id)
+    }
 }
 
 #[cfg(not(feature = "no_map"))]
@@ -22,7 +44,7 @@ pub fn parse_json<R: BufRead, A>(reader: &mut R, data: &mut Data<A, CacheRef>) -
         offsets: false,
         prefer_seek: false
     };
-    for ln in reader.lines() {
+    for (n, ln) in reader.lines().enumerate() {
         let ln = ln?;
         if ln.is_empty() {
             break;
@@ -30,28 +52,60 @@ pub fn parse_json<R: BufRead, A>(reader: &mut R, data: &mut Data<A, CacheRef>) -
 
         // Replace `\\` to `\`
         let ln = ln.replace(r#"\\"#, r#"\"#);
-        let parsed: Row = serde_json::from_str(&ln).unwrap();
+        let parsed: Row = serde_json::from_str(&ln)
+            .map_err(|e| format_err!("Line {}: {}", n + 1, e))?;
         let ts = NaiveDateTime::from_timestamp(parsed.created_at_timestamp, 0);
+        let kind = parsed.kind.as_ref().map_or(COMMENTS, String::as_str);
+        let meta = Meta {
+            id: parsed.event_id(),
+            story_id: parsed.story_id,
+            rating: parsed.rating,
+        };
 
-        match data.ids.entry(parsed.author_id) {
+        match data.cache_mut().ids.entry(parsed.author_id) {
             Entry::Occupied(occ) => {
                 let idx = occ.get().0;
-                data.cached[idx].comments.push(ts);
+                let user = &mut data.cache_mut().cached[idx];
+                user.push_event(kind, ts, meta);
+                user.seen_as(&parsed.author_username, ts);
             },
             Entry::Vacant(_) => {
-                data.put_cache(UserInfo {
+                let mut user = UserInfo {
+                    aliases: vec![Alias {
+                        name: parsed.author_username.clone(),
+                        first_seen: ts,
+                        last_seen: ts
+                    }],
                     name: parsed.author_username,
                     pikabu_id: parsed.author_id,
-                    comments: vec![ts],
+                    comments: Vec::new(),
                     seek: None,
-                }, cfg);
+                    streams: Vec::new(),
+                    meta: Vec::new(),
+                };
+                user.push_event(kind, ts, meta);
+                data.put_cache(user, cfg);
             }
         }
     }
 
-    for i in &mut data.cached {
-        i.comments.sort();
+    let cache = data.cache_mut();
+    // Names are known only now, after renames
+    cache.names.clear();
+    for (idx, i) in cache.cached.iter_mut().enumerate() {
+        i.sort_events();
+        if i.aliases.len() > 1 {
+            // User was renamed, so the most recent name is the current one
+            i.aliases.sort_by_key(|x| x.first_seen);
+            if let Some(last) = i.aliases.iter().max_by_key(|x| x.last_seen) {
+                i.name = last.name.clone();
+            }
+        } else {
+            i.aliases.clear();
+        }
+        insert_names(&mut cache.names, i, CacheRef(idx));
     }
+    data.mark_complete(cfg.into());
 
     Ok(())
 }
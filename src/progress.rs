@@ -1,19 +1,222 @@
-use indicatif::ProgressBar;
+use indicatif::{ProgressBar, HumanBytes};
+use parking_lot::Mutex;
+use serde::Serialize;
 use crate::Res;
+use crate::shared::Fork;
 use std::io::prelude::*;
 use std::io::{Error, SeekFrom};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressMode {
+    Bar,
+    /// One json object per line on stderr, for scripts
+    Json,
+    None,
+}
+
+impl std::str::FromStr for ProgressMode {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Res<Self> {
+        Ok(match s {
+            "bar" => ProgressMode::Bar,
+            "json" => ProgressMode::Json,
+            "none" => ProgressMode::None,
+            _ => return Err(format_err!("Unknown progress mode: {}. Use bar, json or none", s))
+        })
+    }
+}
+
+static MODE: AtomicUsize = AtomicUsize::new(0);
+
+/// Applies to progress created later
+pub fn set_mode(mode: ProgressMode) {
+    MODE.store(mode as usize, Ordering::Relaxed);
+}
+
+pub fn mode() -> ProgressMode {
+    match MODE.load(Ordering::Relaxed) {
+        0 => ProgressMode::Bar,
+        1 => ProgressMode::Json,
+        _ => ProgressMode::None,
+    }
+}
+
+/// How often progress is printed in json mode
+const JSON_INTERVAL: Duration = Duration::from_secs(1);
+/// How often counters in message of bar are updated
+const BAR_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Serialize)]
+struct Report<'a> {
+    /// Position in input before decompression
+    read: u64,
+    total: Option<u64>,
+    decompressed: u64,
+    users: u64,
+    elapsed: f64,
+    /// Estimated from `read`, so compressed input has correct estimate too
+    eta: Option<f64>,
+    message: &'a str,
+    done: bool,
+}
+
+struct State {
+    length: u64,
+    position: AtomicU64,
+    decompressed: AtomicU64,
+    /// `users_read()` at start, so only users of this input are counted
+    users_start: u64,
+    start: Instant,
+    last_report: Mutex<Instant>,
+    message: Mutex<String>,
+}
+
+/// Progress of reading one input. Clones report to the same place
+#[derive(Clone)]
+pub struct Progress {
+    bar: ProgressBar,
+    mode: ProgressMode,
+    state: Arc<State>,
+}
+
+impl Progress {
+    pub fn new(length: u64) -> Self {
+        let mode = mode();
+        let bar = if mode == ProgressMode::Bar {
+            let sty = if length == 0 {
+                ProgressStyle::UnknownBytes
+            } else {
+                ProgressStyle::Bytes
+            };
+            sty.make(length)
+        } else {
+            ProgressBar::hidden()
+        };
+        let now = Instant::now();
+        Progress {
+            bar,
+            mode,
+            state: Arc::new(State {
+                length,
+                position: AtomicU64::new(0),
+                decompressed: AtomicU64::new(0),
+                users_start: crate::data::users_read(),
+                start: now,
+                last_report: Mutex::new(now),
+                message: Mutex::new(String::new()),
+            })
+        }
+    }
+
+    pub fn position(&self) -> u64 {
+        self.state.position.load(Ordering::Relaxed)
+    }
+
+    pub fn set_position(&self, pos: u64) {
+        self.state.position.store(pos, Ordering::Relaxed);
+        self.bar.set_position(pos);
+        self.tick();
+    }
+
+    pub fn inc(&self, delta: u64) {
+        self.state.position.fetch_add(delta, Ordering::Relaxed);
+        self.bar.inc(delta);
+        self.tick();
+    }
+
+    /// Bytes after decompression
+    pub fn inc_decompressed(&self, delta: u64) {
+        self.state.decompressed.fetch_add(delta, Ordering::Relaxed);
+    }
+
+    pub fn set_message(&self, msg: &str) {
+        *self.state.message.lock() = msg.to_string();
+        self.report(false);
+    }
+
+    pub fn finish(&self) {
+        self.report(true);
+        self.bar.finish();
+    }
+
+    fn tick(&self) {
+        let interval = match self.mode {
+            ProgressMode::Bar => BAR_INTERVAL,
+            ProgressMode::Json => JSON_INTERVAL,
+            ProgressMode::None => return,
+        };
+        // Another thread is reporting right now, so this one can skip
+        let mut last = match self.state.last_report.try_lock() {
+            Some(x) => x,
+            None => return
+        };
+        if last.elapsed() < interval {
+            return;
+        }
+        *last = Instant::now();
+        drop(last);
+        self.report(false);
+    }
+
+    fn report(&self, done: bool) {
+        let state = &self.state;
+        let read = self.position();
+        let decompressed = state.decompressed.load(Ordering::Relaxed);
+        let users = crate::data::users_read() - state.users_start;
+        let message = state.message.lock();
+        match self.mode {
+            ProgressMode::Bar => {
+                self.bar.set_message(&format!("{} users, {} decompressed {}", users, HumanBytes(decompressed), message));
+            }
+            ProgressMode::Json => {
+                let elapsed = state.start.elapsed().as_secs_f64();
+                let eta = if state.length == 0 || read == 0 || done {
+                    None
+                } else {
+                    Some(elapsed * state.length.saturating_sub(read) as f64 / read as f64)
+                };
+                let report = Report {
+                    read,
+                    total: if state.length == 0 { None } else { Some(state.length) },
+                    decompressed,
+                    users,
+                    elapsed,
+                    eta,
+                    message: &message,
+                    done,
+                };
+                if let Ok(line) = serde_json::to_string(&report) {
+                    eprintln!("{}", line);
+                }
+            }
+            ProgressMode::None => {}
+        }
+    }
+}
+
+/// Counts bytes of reader. Usually wraps raw input, so position matches the file
 pub struct ReaderWrapper<R> {
     pub reader: R,
-    pub bar: ProgressBar,
+    pub bar: Progress,
     length: u64,
+    track_seeks: bool,
+    /// Reads are counted as decompressed bytes instead of position
+    decompressed: bool,
 }
 
 impl<R: Read> Read for ReaderWrapper<R> {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
         let res = self.reader.read(buf);
         if let Ok(num) = res {
-            self.bar.inc(num as u64);
+            if self.decompressed {
+                self.bar.inc_decompressed(num as u64);
+            } else {
+                self.bar.inc(num as u64);
+            }
         }
         res
     }
@@ -21,6 +224,9 @@ impl<R: Read> Read for ReaderWrapper<R> {
 
 impl<R: Seek> Seek for ReaderWrapper<R> {
     fn seek(&mut self, pos: SeekFrom) -> Result<u64, Error> {
+        if !self.track_seeks {
+            return self.reader.seek(pos);
+        }
         match pos {
             SeekFrom::Start(x) => self.bar.set_position(x),
             SeekFrom::End(x) => {
@@ -40,17 +246,22 @@ impl<R: Seek> Seek for ReaderWrapper<R> {
         }
         self.reader.seek(pos)
     }
+
+    /// Inner reader may be buffered, asking it for position does not drop the buffer
+    fn stream_position(&mut self) -> Result<u64, Error> {
+        self.reader.stream_position()
+    }
 }
 
 impl<R> ReaderWrapper<R> {
     pub fn new(reader: R, length: u64) -> Self {
-        let sty = if length == 0 {
-            ProgressStyle::UnknownBytes
-        } else {
-            ProgressStyle::Bytes
-        };
-        let bar = sty.make(length);
-        Self { reader, length, bar }
+        let bar = Progress::new(length);
+        Self { reader, length, bar, track_seeks: true, decompressed: false }
+    }
+
+    /// Wraps output of decompression, bytes are added to existing progress
+    pub fn decompressed(reader: R, bar: Progress) -> Self {
+        Self { reader, length: 0, bar, track_seeks: false, decompressed: true }
     }
 
     pub fn message(self, msg: &str) -> Self {
@@ -59,6 +270,30 @@ impl<R> ReaderWrapper<R> {
     }
 }
 
+impl<R: Fork> Fork for ReaderWrapper<R> {
+    /// Forks are read concurrently, so they only add bytes read to the shared bar
+    fn fork(&self) -> Result<Self, Error> {
+        Ok(ReaderWrapper {
+            reader: self.reader.fork()?,
+            bar: self.bar.clone(),
+            length: self.length,
+            track_seeks: false,
+            decompressed: self.decompressed
+        })
+    }
+
+    fn is_block_compressed(&self) -> bool {
+        self.reader.is_block_compressed()
+    }
+}
+
+impl ReaderWrapper<crate::shared::SharedFile> {
+    pub fn from_shared(f: crate::shared::SharedFile) -> Self {
+        let len = f.len();
+        Self::new(f, len)
+    }
+}
+
 impl ReaderWrapper<std::fs::File> {
     pub fn from_file(f: std::fs::File) -> Self {
         let len = f.metadata().map(|x| x.len()).unwrap_or_default();
@@ -89,14 +324,13 @@ impl ProgressStyle {
         res
     }
 
-    #[cfg(not(feature="no_progress"))]
+    /// Hidden unless progress is shown as bar
     pub fn make(self, len: u64) -> ProgressBar {
-        ProgressBar::new(len).with_style(self.into())
-    }
-
-    #[cfg(feature="no_progress")]
-    pub fn make(self, _len: u64) -> ProgressBar {
-        ProgressBar::hidden()
+        if mode() == ProgressMode::Bar {
+            ProgressBar::new(len).with_style(self.into())
+        } else {
+            ProgressBar::hidden()
+        }
     }
 
     pub fn file(self, f: std::fs::File) -> ProgressBar {
@@ -111,30 +345,29 @@ impl ProgressStyle {
     }
 }
 
-impl Into<indicatif::ProgressStyle> for ProgressStyle {
-    fn into(self) -> indicatif::ProgressStyle {
-        use indicatif::ProgressStyle;
-        match self {
-            Self::UnknownBytes => {
-                ProgressStyle::default_bar()
+impl From<ProgressStyle> for indicatif::ProgressStyle {
+    fn from(style: ProgressStyle) -> Self {
+        match style {
+            ProgressStyle::UnknownBytes => {
+                Self::default_bar()
                     .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/??? ({bytes_per_sec}) {msg}")
                     .progress_chars("#>-")
             }
-            Self::Bytes => {
-                ProgressStyle::default_bar()
+            ProgressStyle::Bytes => {
+                Self::default_bar()
                     .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec} {eta}) {msg}")
                     .progress_chars("#>-")
             }
-            Self::UnknownItems => {
-                ProgressStyle::default_bar()
+            ProgressStyle::UnknownItems => {
+                Self::default_bar()
                     .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/??? ({per_sec}) {msg}")
                     .progress_chars("##-")
             }
-            Self::Items => {
-                ProgressStyle::default_bar()
+            ProgressStyle::Items => {
+                Self::default_bar()
                     .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos:>7}/{len:7} ({per_sec} {eta}) {msg}")
                     .progress_chars("##-")
             }
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,244 @@
+use chrono::{NaiveDateTime, Timelike};
+use streaming_iterator::StreamingIterator;
+use serde::Serialize;
+use crate::data::*;
+use crate::Res;
+
+/// Longest period between events checked for periodicity, in minutes
+const MAX_LAG: usize = 180;
+/// Hours with smaller share of comments are treated as empty
+const QUIET_HOUR: f64 = 0.01;
+/// Humans usually sleep longer
+const SLEEP_HOURS: usize = 6;
+/// Added to number of pairs at neighbouring lags, so few random pairs do not make a peak
+const PAIRS_NOISE: f64 = 5.0;
+
+/// Heuristics of automated activity. Every component is from 0 (human) to 1 (bot)
+#[derive(Debug, Clone, Serialize)]
+pub struct BotScore {
+    pub pikabu_id: i64,
+    pub name: String,
+    pub comments: usize,
+    /// Longest run of quiet hours of day, wrapping around midnight
+    pub sleep_gap: usize,
+    /// Highest peak of autocorrelation relative to neighbouring lags, 1 when there are no peaks
+    pub periodicity: f64,
+    /// Lag of that peak, in minutes
+    pub period: usize,
+    /// `(σ - μ) / (σ + μ)` of intervals: -1 is perfectly regular, 0 is random, 1 is bursty
+    pub burstiness: f64,
+    /// Share of comments written in the same second as previous one
+    pub same_second: f64,
+    /// Mean of all components, users are ranked by it
+    pub score: f64,
+}
+
+/// Timezone does not matter, because hours wrap around midnight
+fn sleep_gap(points: &[NaiveDateTime]) -> usize {
+    // Otherwise no hour is below the share of nothing, as if user never slept
+    if points.is_empty() {
+        return 24;
+    }
+    let mut hours = [0usize; 24];
+    for p in points {
+        hours[p.hour() as usize] += 1;
+    }
+    let quiet: Vec<bool> = hours.iter().map(|x| (*x as f64) < QUIET_HOUR * points.len() as f64).collect();
+    if quiet.iter().all(|x| *x) {
+        return 24;
+    }
+    // Day is repeated, so runs through midnight are found too
+    let (mut best, mut run) = (0, 0);
+    for q in quiet.iter().chain(quiet.iter()) {
+        run = if *q { run + 1 } else { 0 };
+        best = best.max(run);
+    }
+    best
+}
+
+/// Number of pairs of events exactly `lag` minutes apart is autocorrelation of per-minute activity.
+/// Periodic events give a spike at their period, while human activity decays smoothly
+fn periodicity(points: &[NaiveDateTime]) -> (f64, usize) {
+    let minutes: Vec<i64> = points.iter().map(|x| x.timestamp() / 60).collect();
+    let mut pairs = [0u64; MAX_LAG + 2];
+    for (i, a) in minutes.iter().enumerate() {
+        for b in &minutes[i + 1..] {
+            let lag = (b - a) as usize;
+            if lag > MAX_LAG + 1 {
+                break;
+            }
+            pairs[lag] += 1;
+        }
+    }
+    let mut best = (1.0, 0);
+    for lag in 2..=MAX_LAG {
+        let around = (pairs[lag - 1] + pairs[lag + 1]) as f64 / 2.0;
+        let peak = pairs[lag] as f64 / (around + PAIRS_NOISE);
+        if peak > best.0 {
+            best = (peak, lag);
+        }
+    }
+    best
+}
+
+fn burstiness(points: &[NaiveDateTime]) -> f64 {
+    let intervals: Vec<f64> = points.windows(2)
+        .map(|x| (x[1] - x[0]).num_seconds() as f64)
+        .collect();
+    if intervals.is_empty() {
+        return 0.0;
+    }
+    let mean = intervals.iter().sum::<f64>() / intervals.len() as f64;
+    let std = (intervals.iter().map(|x| (x - mean) * (x - mean)).sum::<f64>() / intervals.len() as f64).sqrt();
+    if mean + std == 0.0 { -1.0 } else { (std - mean) / (std + mean) }
+}
+
+impl BotScore {
+    /// Points must be sorted
+    pub fn new(user: &UserInfo, points: &[NaiveDateTime]) -> Self {
+        let sleep_gap = sleep_gap(points);
+        let (periodicity, period) = periodicity(points);
+        let burstiness = burstiness(points);
+        let same = points.windows(2).filter(|x| x[0] == x[1]).count();
+        let same_second = if points.is_empty() { 0.0 } else { same as f64 / points.len() as f64 };
+
+        let components = [
+            1.0 - sleep_gap.min(SLEEP_HOURS) as f64 / SLEEP_HOURS as f64,
+            1.0 - 1.0 / periodicity,
+            (1.0 - burstiness) / 2.0,
+            same_second,
+        ];
+        BotScore {
+            pikabu_id: user.pikabu_id,
+            name: user.name.clone(),
+            comments: points.len(),
+            sleep_gap,
+            periodicity,
+            period,
+            burstiness,
+            same_second,
+            score: components.iter().sum::<f64>() / components.len() as f64,
+        }
+    }
+}
+
+pub struct ScoreSettings {
+    pub top: usize,
+    /// Users with less comments are too noisy to score
+    pub min_comments: usize,
+}
+
+/// Scores every user in data, returns the most bot-like ones with their data
+pub fn find_bots<D: SimpleData>(data: &mut D, settings: ScoreSettings) -> Res<Vec<(BotScore, UserInfo)>> {
+    let mut top: Vec<(BotScore, UserInfo)> = Vec::with_capacity(settings.top + 1);
+    let mut reader = data.get_reader(ReadConfig::None)?;
+    while let Some(user) = reader.next() {
+        if user.comments.len() < settings.min_comments {
+            continue;
+        }
+        let score = BotScore::new(user, &user.comments);
+        if top.len() == settings.top && top.last().map_or(true, |x| x.0.score >= score.score) {
+            continue;
+        }
+        let idx = top.iter().position(|x| x.0.score < score.score).unwrap_or(top.len());
+        top.insert(idx, (score, user.clone()));
+        top.truncate(settings.top);
+    }
+    reader.finish()?;
+    Ok(top)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDate, Duration};
+
+    fn at(minute: i64) -> NaiveDateTime {
+        NaiveDate::from_ymd(2017, 7, 10).and_hms(0, 0, 0) + Duration::minutes(minute)
+    }
+
+    fn user(pikabu_id: i64, comments: Vec<NaiveDateTime>) -> UserInfo {
+        UserInfo {
+            name: format!("user{}", pikabu_id),
+            pikabu_id,
+            seek: None,
+            comments,
+            aliases: Vec::new(),
+            streams: Vec::new(),
+            meta: Vec::new(),
+        }
+    }
+
+    fn score(points: Vec<NaiveDateTime>) -> BotScore {
+        let user = user(1, points);
+        BotScore::new(&user, &user.comments)
+    }
+
+    /// Comments from 8:00 to 23:00 at uneven times, several days
+    fn human() -> Vec<NaiveDateTime> {
+        let mut seed = 12345u64;
+        let mut points: Vec<NaiveDateTime> = (0..200)
+            .map(|_| {
+                seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                let x = (seed >> 33) as i64;
+                at(x % 10 * 24 * 60 + 8 * 60 + x / 10 % (15 * 60))
+            })
+            .collect();
+        points.sort();
+        points
+    }
+
+    #[test]
+    fn empty_user() {
+        let x = score(Vec::new());
+        assert_eq!((x.comments, x.sleep_gap, x.period), (0, 24, 0));
+        assert_eq!((x.periodicity, x.burstiness, x.same_second), (1.0, 0.0, 0.0));
+        assert!(x.score.is_finite());
+    }
+
+    #[test]
+    fn single_comment() {
+        let x = score(vec![at(10 * 60)]);
+        assert_eq!((x.comments, x.sleep_gap, x.period), (1, 23, 0));
+        assert_eq!((x.periodicity, x.burstiness, x.same_second), (1.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn identical_timestamps() {
+        let x = score(vec![at(10 * 60); 5]);
+        assert_eq!(x.burstiness, -1.0);
+        assert_eq!(x.same_second, 0.8);
+        assert_eq!(x.period, 0);
+    }
+
+    #[test]
+    fn autocorrelation_finds_period() {
+        let x = score((0..100).map(|i| at(i * 30)).collect());
+        assert_eq!((x.period, x.sleep_gap, x.burstiness), (30, 0, -1.0));
+        // 99 pairs 30 minutes apart and none at 29 or 31
+        assert_eq!(x.periodicity, 99.0 / PAIRS_NOISE);
+
+        let human = score(human());
+        assert!(human.periodicity < 2.0);
+        assert!(human.sleep_gap >= 8);
+        assert!(human.score < x.score);
+    }
+
+    #[test]
+    fn ranks_bots_first() {
+        let mut buf = Vec::new();
+        let users = [
+            user(1, human()),
+            user(2, (0..100).map(|i| at(i * 30)).collect()),
+            user(3, vec![at(0); 5]),
+        ];
+        for x in &users {
+            write_chunk(&mut buf, Some(x)).unwrap();
+        }
+        write_chunk(&mut buf, None).unwrap();
+        let mut data: Data<_, CacheRef> = Data::new(&buf[..]);
+        let found = find_bots(&mut data, ScoreSettings { top: 5, min_comments: 10 }).unwrap();
+        let ids: Vec<i64> = found.iter().map(|x| x.0.pikabu_id).collect();
+        assert_eq!(ids, vec![2, 1]);
+    }
+}
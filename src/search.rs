@@ -2,6 +2,7 @@ use crate::data::*;
 use crate::Res;
 use std::collections::{HashMap, HashSet};
 use std::hash::Hash;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use regex;
 use streaming_iterator::StreamingIterator;
 
@@ -18,18 +19,14 @@ impl UserSelector {
     pub fn new(s: &str) -> Res<Self> {
         // Since we are removing characters from beginning it is impossible to make it faster
         // than O(n) without too much hassle, so .to_owned is fine
-        if s.starts_with("re:") {
-            let s = &s[3..];
+        if let Some(s) = s.strip_prefix("re:") {
             Ok(UserSelector::Regexp(s.to_lowercase()))
-        } else if s.starts_with("gl:") {
-            let s = &s[3..];
+        } else if let Some(s) = s.strip_prefix("gl:") {
             Ok(UserSelector::Glob(s.to_lowercase()))
-        } else if s.starts_with("id:") {
-            let s = &s[3..];
+        } else if let Some(s) = s.strip_prefix("id:") {
             let id = s.parse()?;
             Ok(UserSelector::PikabuId(id))
-        } else if s.starts_with("sk:") {
-            let s = &s[3..];
+        } else if let Some(s) = s.strip_prefix("sk:") {
             let sk = s.parse()?;
             Ok(UserSelector::Seek(sk))
         } else {
@@ -78,6 +75,25 @@ enum Compiled<'a> {
 }
 
 impl<'a> Compiled<'a> {
+    /// `name` should be lowercase
+    fn matches_name(&self, name: &str) -> bool {
+        match self {
+            Compiled::Name(n) => *n == name,
+            Compiled::Glob(gl) => gl.matches(name),
+            Compiled::Regexp(re) => re.reg.is_match(name),
+            Compiled::PikabuId(_) | Compiled::Seek(_) => false
+        }
+    }
+
+    fn matches(&self, user: &UserInfo) -> bool {
+        match self {
+            Compiled::PikabuId(id) => *id == user.pikabu_id,
+            Compiled::Seek(s) => Some(*s as usize) == user.seek,
+            // Former names are matched too
+            _ => user.names().any(|x| self.matches_name(&x.to_lowercase()))
+        }
+    }
+
     fn compile(raw: &[UserSelector]) -> Res<Vec<Compiled>> {
         // FIXME: Cloning in .entry(...) can be avoided somehow. But IDK how.
         let mut regexps = HashMap::new();
@@ -86,7 +102,7 @@ impl<'a> Compiled<'a> {
             UserSelector::PikabuId(x) => Ok(Compiled::PikabuId(*x)),
             UserSelector::Regexp(r) => regexps
                 .entry(r.clone())
-                .or_insert_with(|| regex::RegexBuilder::new(&r)
+                .or_insert_with(|| regex::RegexBuilder::new(r)
                     .case_insensitive(true)
                     .size_limit(5*1024*1024) // 5Mb
                     .nest_limit(5)
@@ -101,7 +117,7 @@ impl<'a> Compiled<'a> {
             UserSelector::Name(name) => Ok(Compiled::Name(name)),
             UserSelector::Glob(gl) => globs
                 .entry(gl.clone())
-                .or_insert_with(|| glob::Pattern::new(&gl)
+                .or_insert_with(|| glob::Pattern::new(gl)
                     .map(Compiled::Glob)
                     // Because PatternError does not implement Clone.
                     // failure::Error does not implement too, so don't use format_err!(...)
@@ -113,9 +129,114 @@ impl<'a> Compiled<'a> {
     }
 }
 
+/// Search found more users than `SearchSettings::limit` allows
+#[derive(Debug)]
+pub struct LimitReached;
+
+impl std::fmt::Display for LimitReached {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Limit reached!")
+    }
+}
+
+impl failure::Fail for LimitReached {}
+
 pub struct SearchSettings {
     pub use_cache: bool,
-    pub limit: usize  // Returns error if limit is reached
+    pub limit: usize,  // Returns error if limit is reached
+    pub threads: usize,  // Full scan is done concurrently when data can be split
+}
+
+impl SearchSettings {
+    fn take(&mut self) -> Res<()> {
+        self.limit = self.limit.saturating_sub(1);
+        if self.limit == 0 {
+            Err(LimitReached.into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// How users matching a selector are found
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Access {
+    /// Single lookup in names map
+    NameMap,
+    /// Single lookup in ids map
+    IdMap,
+    /// Reading at the offset. Offsets map is used when possible
+    Offset,
+    /// Matching every key of names map
+    NameScan,
+    /// Reading the whole file
+    FullScan,
+}
+
+impl Access {
+    fn choose(selector: &UserSelector, maps: Maps, use_cache: bool, seekable: bool) -> Self {
+        // Incomplete map may silently miss some users, so it is never used
+        let maps = if use_cache { maps } else { Maps::default() };
+        match selector {
+            UserSelector::Seek(_) if seekable => Access::Offset,
+            UserSelector::Name(_) if maps.names => Access::NameMap,
+            UserSelector::PikabuId(_) if maps.ids => Access::IdMap,
+            UserSelector::Glob(_) | UserSelector::Regexp(_) if maps.names => Access::NameScan,
+            _ => Access::FullScan
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Plan {
+    pub steps: Vec<(UserSelector, Access)>,
+}
+
+impl Plan {
+    fn new<D: SimpleData>(data: &D, query: &[Vec<UserSelector>], settings: &SearchSettings, seekable: bool) -> Self {
+        let maps = data.complete_maps();
+        let mut steps: Vec<(UserSelector, Access)> = Vec::new();
+        for selector in query.iter().flatten() {
+            if steps.iter().all(|(s, _)| s != selector) {
+                let access = Access::choose(selector, maps, settings.use_cache, seekable);
+                steps.push((selector.clone(), access));
+            }
+        }
+        Plan { steps }
+    }
+
+    pub fn access(&self, selector: &UserSelector) -> Access {
+        self.steps.iter()
+            .find(|(s, _)| s == selector)
+            .map(|(_, a)| *a)
+            .unwrap_or(Access::FullScan)
+    }
+
+    pub fn full_scan(&self) -> bool {
+        self.steps.iter().any(|(_, a)| *a == Access::FullScan)
+    }
+}
+
+impl std::fmt::Display for Plan {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (i, (selector, access)) in self.steps.iter().enumerate() {
+            if i != 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{} -> {:?}", selector.human_readable(), access)?;
+        }
+        Ok(())
+    }
+}
+
+/// Plan which will be used by `find(...)`
+pub fn plan<D: SimpleData>(data: &D, query: &[Vec<UserSelector>], settings: &SearchSettings) -> Plan {
+    Plan::new(data, query, settings, false)
+}
+
+/// Plan which will be used by `find_seek(...)`
+pub fn plan_seek<D: SimpleData+SeekableData>(data: &D, query: &[Vec<UserSelector>], settings: &SearchSettings) -> Plan {
+    Plan::new(data, query, settings, true)
 }
 
 pub fn find_seek<D: SimpleData+SeekableData>(data: &mut D, query: Vec<Vec<UserSelector>>, mut settings: SearchSettings) -> Res<Vec<Vec<UserInfo>>> {
@@ -130,7 +251,7 @@ pub fn find_seek<D: SimpleData+SeekableData>(data: &mut D, query: Vec<Vec<UserSe
                 let user = data.by_offset(sk as usize)?.into_cow(data)
                     .ok_or_else(|| format_err!("by_offset returned None"))?;
                 tmp_sk.push(user.into_owned());
-                settings.limit -= 1; if settings.limit == 0 {return Err(format_err!("Limit reached!"))}
+                settings.take()?;
             } else {
                 tmp.push(j)
             }
@@ -146,116 +267,156 @@ pub fn find_seek<D: SimpleData+SeekableData>(data: &mut D, query: Vec<Vec<UserSe
     Ok(other)
 }
 
+struct Target<'a, 'b> {
+    selector: &'b Compiled<'a>,
+    access: Access,
+    found: Vec<ReaderValue>,
+}
+
+/// Matches users from every segment in its own thread. Results are in file order.
+/// Finding `limit` users is an error, every thread stops then
+fn scan_parallel(segments: Vec<Segment>, targets: &[Target], limit: usize) -> Res<Vec<(usize, UserInfo)>> {
+    let scanned: Vec<(usize, &Compiled)> = targets.iter()
+        .enumerate()
+        .filter(|(_, t)| t.access == Access::FullScan)
+        .map(|(idx, t)| (idx, t.selector))
+        .collect();
+    let scanned = &scanned;
+    let total = &AtomicUsize::new(0);
+    let results: Vec<Res<Vec<_>>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = segments.into_iter()
+            .map(|segment| scope.spawn(move || {
+                let mut found = Vec::new();
+                for user in segment {
+                    // Some thread has reached the limit already
+                    if total.load(Ordering::Relaxed) >= limit {
+                        break;
+                    }
+                    let user = user?;
+                    for (idx, selector) in scanned {
+                        if selector.matches(&user) {
+                            found.push((*idx, user.clone()));
+                            if total.fetch_add(1, Ordering::Relaxed) + 1 >= limit {
+                                return Err(LimitReached.into());
+                            }
+                        }
+                    }
+                }
+                Ok(found)
+            }))
+            .collect();
+        handles.into_iter()
+            .map(|h| h.join().unwrap_or_else(|_| Err(format_err!("Search thread panicked"))))
+            .collect()
+    });
+    let mut res = Vec::new();
+    for r in results {
+        res.append(&mut r?);
+    }
+    Ok(res)
+}
+
 pub fn find<D: SimpleData>(data: &mut D, query: &[Vec<UserSelector>], mut settings: SearchSettings) -> Res<Vec<Vec<UserInfo>>> {
-    let compiled: Res<Vec<_>> = query.iter().map(|x| Compiled::compile(&x)).collect();
+    if query.iter().flatten().any(|x| matches!(x, UserSelector::Seek(_))) {
+        return Err(format_err!("Seek search not available. Use find_seek(...) instead"));
+    }
+    let plan = plan(data, query, &settings);
+    let compiled: Res<Vec<_>> = query.iter().map(|x| Compiled::compile(x)).collect();
     let compiled = compiled?;
 
-    let mut to_find = HashMap::new();
-    let mut iter_all = false;
-    for constraints in &compiled {
-        for selector in constraints {
-            if let Compiled::Glob(_) | Compiled::Regexp(_) = selector {
-                iter_all = true;
-            }
-            to_find.entry(selector).or_insert_with(Vec::new);
+    let mut targets = Vec::new();
+    // Regexps are hashed by their source only, so the cache inside of them does not matter
+    #[allow(clippy::mutable_key_type)]
+    let mut indices = HashMap::new();
+    for (constraints, raw) in compiled.iter().zip(query) {
+        for (selector, raw) in constraints.iter().zip(raw) {
+            indices.entry(selector).or_insert_with(|| {
+                targets.push(Target {
+                    selector,
+                    access: plan.access(raw),
+                    found: Vec::new()
+                });
+                targets.len() - 1
+            });
         }
     }
 
-    if iter_all || !settings.use_cache {
-        let mut pending = if iter_all {
-            std::usize::MAX  // This program won't work when there is too much users
-        } else {
-            to_find.len() // Only names and id's, so only one user for each selector
+    for t in &mut targets {
+        let vals = match (t.access, t.selector) {
+            (Access::NameMap, Compiled::Name(n)) => data.by_name(n)?,
+            (Access::IdMap, Compiled::PikabuId(id)) => vec![data.by_id(*id)?],
+            _ => continue
         };
-        if !settings.use_cache {
-            let mut reader = data.get_reader(ReadConfig::None)?;
-            'l1: while let Some(user) = reader.next() {
-                for (c, v) in &mut to_find {
-                    let matches = match c {
-                        Compiled::Name(n) => n == &user.name.to_lowercase(),
-                        Compiled::Glob(gl) => gl.matches_with(&user.name, glob::MatchOptions {
-                            case_sensitive: false,
-                            require_literal_separator: false,
-                            require_literal_leading_dot: false
-                        }),
-                        Compiled::Regexp(re) => re.reg.is_match(&user.name),
-                        Compiled::PikabuId(id) => *id == user.pikabu_id,
-                        Compiled::Seek(s) => Some(*s as usize) == user.seek,
-                    };
-                    if matches {
-                        settings.limit -= 1; if settings.limit == 0 {return Err(format_err!("Limit reached!"))}
-                        v.push(ReaderValue::Owned(user.clone()));
-                        pending -= 1;
-                        if pending == 0 {
-                            break 'l1;
-                        }
+        for val in vals {
+            if let Some(x) = val.into_cow(data) {
+                settings.take()?;
+                t.found.push(ReaderValue::Owned(x.into_owned()))
+            }
+        }
+    }
+
+    if targets.iter().any(|t| t.access == Access::NameScan) {
+        // Former names point to the same user as current, so report each user only once
+        let mut seen = HashSet::new();
+        for (name, owners) in data.iter_names() {
+            for (idx, t) in targets.iter_mut().enumerate() {
+                if t.access != Access::NameScan || !t.selector.matches_name(name) {
+                    continue;
+                }
+                for r in owners {
+                    if seen.insert((idx, *r)) {
+                        settings.take()?;
+                        t.found.push(data.get(*r)?);
                     }
                 }
             }
+        }
+    }
+
+    if targets.iter().any(|t| t.access == Access::FullScan) {
+        // Ids are unique, so when only ids are scanned we can stop early
+        let scanned = targets.iter().filter(|t| t.access == Access::FullScan);
+        let mut pending = if scanned.clone().all(|t| matches!(t.selector, Compiled::PikabuId(_))) {
+            scanned.count()
         } else {
-            'l2: for (name, r) in data.iter_names() {
-                for (c, v) in &mut to_find {
-                    let matches = match c {
-                        Compiled::Name(n) => name == n,
-                        Compiled::Glob(gl) => gl.matches(name),
-                        Compiled::Regexp(re) => re.reg.is_match(name),
-                        Compiled::Seek(_) =>
-                            return Err(format_err!("Seek search not available. Use find_seek(...) instead")),
-                        _ => false
-                    };
-                    if matches {
-                        settings.limit -= 1; if settings.limit == 0 {return Err(format_err!("Limit reached!"))}
-                        v.push(data.get(*r)?);
+            usize::MAX
+        };
+        let segments = if settings.threads > 1 && pending == usize::MAX {
+            data.split(settings.threads)?
+        } else {
+            Vec::new()
+        };
+        if segments.is_empty() {
+            let mut reader = data.get_reader(ReadConfig::None)?;
+            while let Some(user) = reader.next() {
+                for t in targets.iter_mut().filter(|t| t.access == Access::FullScan) {
+                    if t.selector.matches(user) {
+                        settings.take()?;
+                        t.found.push(ReaderValue::Owned(user.clone()));
                         pending -= 1;
-                        if pending == 0 {
-                            break 'l2;
-                        }
                     }
                 }
-            }
-            'l3: for (id, r) in data.iter_ids() {
-                for (c, v) in &mut to_find {
-                    match c {
-                        Compiled::PikabuId(i) if i == id => {
-                            settings.limit -= 1; if settings.limit == 0 {return Err(format_err!("Limit reached!"))}
-                            v.push(data.get(*r)?);
-                            pending -= 1;
-                            if pending == 0 {
-                                break 'l3;
-                            }
-                        }
-                        _ => {}
-                    }
+                if pending == 0 {
+                    break;
                 }
             }
-        }
-    } else { // !iter_all && use_cache
-        for (c, v) in &mut to_find {
-            let val = match c {
-                Compiled::Glob(_) | Compiled::Regexp(_) =>
-                    return Err(format_err!("Internal searcher error. Invalid constraint in light path")),
-                Compiled::Name(n) => data.by_name(&n),
-                Compiled::PikabuId(id) => data.by_id(*id),
-                Compiled::Seek(_) =>
-                    return Err(format_err!("Seek search not available. Use find_seek(...) instead"))
-            };
-            let cow = val?.into_cow(data);
-            if let Some(x) = cow {
-                settings.limit -= 1; if settings.limit == 0 {return Err(format_err!("Limit reached!"))}
-                v.push(ReaderValue::Owned(x.into_owned()))
+            reader.finish()?;
+        } else {
+            for (idx, user) in scan_parallel(segments, &targets, settings.limit)? {
+                settings.take()?;
+                targets[idx].found.push(ReaderValue::Owned(user));
             }
         }
     }
 
     let result = compiled.iter().map(|constraints| {
         constraints.iter()
-            .map(|selector| {
-                let rs = to_find.get(&selector).unwrap();
+            .flat_map(|selector| {
+                let rs = &targets[indices[selector]].found;
                 rs.iter()
                     .map(|x| x.to_ref(data).unwrap().clone())
                     .collect::<Vec<_>>()
             })
-            .flatten()
             // Dedup:
             .fold((HashSet::new(), Vec::new()), |(mut h, mut r), i| {
                 if h.insert(i.pikabu_id) {
@@ -276,8 +437,143 @@ pub fn selector_name(selectors: &[UserSelector]) -> String {
     let pre_last = selectors.len() - 1;
     for i in &selectors[..pre_last] {
         parts.push_str(&i.human_readable());
-        parts.push_str("+");
+        parts.push('+');
     }
     parts.push_str(&selectors.last().unwrap().human_readable());
     parts
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::SharedFile;
+    use chrono::NaiveDateTime;
+    use parking_lot::Mutex;
+    use std::io::{BufReader, Cursor};
+
+    fn user(pikabu_id: i64, names: &[&str]) -> UserInfo {
+        let ts = |x| NaiveDateTime::from_timestamp(1_500_000_000 + x, 0);
+        UserInfo {
+            name: names.last().unwrap().to_string(),
+            pikabu_id,
+            seek: None,
+            comments: vec![ts(0)],
+            aliases: if names.len() > 1 {
+                names.iter().enumerate().map(|(n, x)| Alias {
+                    name: x.to_string(),
+                    first_seen: ts(n as i64),
+                    last_seen: ts(n as i64),
+                }).collect()
+            } else {
+                Vec::new()
+            },
+            streams: Vec::new(),
+            meta: Vec::new(),
+        }
+    }
+
+    fn ids(found: Vec<Vec<UserInfo>>) -> Vec<Vec<i64>> {
+        found.into_iter()
+            .map(|x| {
+                let mut ids: Vec<i64> = x.iter().map(|u| u.pikabu_id).collect();
+                ids.sort();
+                ids
+            })
+            .collect()
+    }
+
+    type Index = (HashMap<String, Vec<SeekableRef>>, HashMap<i64, SeekableRef>);
+
+    /// "bob" renamed to "alice", then someone else took "bob"
+    fn write_users(test: &str) -> (std::path::PathBuf, Index) {
+        write_file(test, &[user(1, &["bob", "alice"]), user(2, &["bob"]), user(3, &["carol"])])
+    }
+
+    fn write_file(test: &str, users: &[UserInfo]) -> (std::path::PathBuf, Index) {
+        let mut buf = Cursor::new(Vec::new());
+        let mut names = HashMap::new();
+        let mut by_id = HashMap::new();
+        for u in users {
+            let r = SeekableRef::Seek(buf.position() as usize);
+            write_chunk(&mut buf, Some(u)).unwrap();
+            insert_names(&mut names, u, r);
+            by_id.insert(u.pikabu_id, r);
+        }
+        write_chunk(&mut buf, None).unwrap();
+        let path = std::env::temp_dir().join(format!("pikadots-{}-{}.dat", test, std::process::id()));
+        std::fs::write(&path, buf.into_inner()).unwrap();
+        (path, (names, by_id))
+    }
+
+    fn open(path: &std::path::Path) -> Data<Mutex<BufReader<SharedFile>>, SeekableRef> {
+        Data::new(Mutex::new(BufReader::new(SharedFile::open(path).unwrap())))
+    }
+
+    #[test]
+    fn index_finds_same_users_as_scan() {
+        let (path, (names, by_id)) = write_users("search");
+        let open = || open(&path);
+        let query: Vec<Vec<UserSelector>> = ["bob", "alice", "re:^b", "carol"].iter()
+            .map(|x| vec![UserSelector::new(x).unwrap()])
+            .collect();
+        let settings = || SearchSettings { use_cache: true, limit: 100, threads: 1 };
+
+        let scanned = find(&mut open(), &query, settings()).unwrap();
+        let mut indexed = open();
+        indexed.fill_cache(names, by_id);
+        let mapped = find(&mut indexed, &query, settings()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(ids(scanned), vec![vec![1, 2], vec![1], vec![1, 2], vec![3]]);
+        assert_eq!(ids(mapped), vec![vec![1, 2], vec![1], vec![1, 2], vec![3]]);
+    }
+
+    #[test]
+    fn index_and_memory_keep_one_owner() {
+        let (path, (names, by_id)) = write_users("owners");
+        let mut data = open(&path);
+        data.fill_cache(names, by_id);
+        let all = CacheConfig { names: true, ids: true, offsets: true, prefer_seek: false };
+        let mut reader = data.get_reader(ReadConfig::Cache(all)).unwrap();
+        while reader.next().is_some() {}
+        reader.finish().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let found = data.by_name("bob").unwrap();
+        assert_eq!(found.len(), 2);
+        assert!(found.iter().all(|x| matches!(x, ReaderValue::Cached(_))));
+        assert_eq!(data.by_name("Alice").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn seek_needs_find_seek() {
+        let (path, _) = write_users("seek");
+        let query = vec![vec![UserSelector::new("sk:0").unwrap()]];
+        let settings = || SearchSettings { use_cache: true, limit: 100, threads: 1 };
+        assert!(find(&mut open(&path), &query, settings()).is_err());
+        let found = find_seek(&mut open(&path), query, settings()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(ids(found), vec![vec![1]]);
+    }
+
+    #[test]
+    fn parallel_scan_finds_same_users() {
+        let users: Vec<_> = (0..500).map(|x| user(x, &[&format!("user{}", x)])).collect();
+        let (path, _) = write_file("parallel", &users);
+        let query: Vec<Vec<UserSelector>> = ["re:7$", "gl:user1?", "user250", "re:^user4"].iter()
+            .map(|x| vec![UserSelector::new(x).unwrap()])
+            .collect();
+        let settings = |threads, limit| SearchSettings { use_cache: false, limit, threads };
+
+        let mut data = open(&path);
+        assert!(data.split(4).unwrap().len() > 1);
+        let single = find(&mut open(&path), &query, settings(1, 1000)).unwrap();
+        let parallel = find(&mut data, &query, settings(4, 1000)).unwrap();
+        let limited = find(&mut open(&path), &query, settings(4, 100));
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(ids(single.clone()).iter().map(Vec::len).collect::<Vec<_>>(), vec![50, 10, 1, 111]);
+        assert_eq!(ids(single), ids(parallel));
+        assert!(limited.is_err());
+    }
+}
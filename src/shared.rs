@@ -0,0 +1,169 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{self, BufReader, SeekFrom};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::Res;
+use crate::compress::Codec;
+
+static TOTAL_READ: AtomicU64 = AtomicU64::new(0);
+
+/// Bytes read by all shared files since start
+pub fn total_read() -> u64 {
+    TOTAL_READ.load(Ordering::Relaxed)
+}
+
+/// Readers which can create another reader over the same data. Readers must not share position
+pub trait Fork: Sized {
+    fn fork(&self) -> io::Result<Self>;
+
+    /// Offsets are virtual ones of bgzf, so only offsets of records or blocks can be seeked to
+    fn is_block_compressed(&self) -> bool {
+        false
+    }
+}
+
+impl<R: Read + Fork> Fork for BufReader<R> {
+    fn fork(&self) -> io::Result<Self> {
+        Ok(BufReader::with_capacity(self.capacity(), self.get_ref().fork()?))
+    }
+
+    fn is_block_compressed(&self) -> bool {
+        self.get_ref().is_block_compressed()
+    }
+}
+
+/// File which uses positional reads, so it can be read from several threads at once.
+/// Each clone has its own position
+#[derive(Clone, Debug)]
+pub struct SharedFile {
+    file: Arc<File>,
+    pos: u64,
+    len: u64,
+}
+
+impl SharedFile {
+    pub fn new(file: File) -> Res<Self> {
+        let len = file.metadata()?.len();
+        Ok(SharedFile {
+            file: Arc::new(file),
+            pos: 0,
+            len
+        })
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Res<Self> {
+        Self::new(File::open(path)?)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    // Moves file cursor, but nobody relies on it
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+impl Read for SharedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let num = read_at(&self.file, buf, self.pos)?;
+        self.pos += num as u64;
+        TOTAL_READ.fetch_add(num as u64, Ordering::Relaxed);
+        Ok(num)
+    }
+}
+
+impl Seek for SharedFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(x) => x as i64,
+            SeekFrom::End(x) => self.len as i64 + x,
+            SeekFrom::Current(x) => self.pos as i64 + x,
+        };
+        if new_pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Seek before start of file"));
+        }
+        self.pos = new_pos as u64;
+        Ok(self.pos)
+    }
+}
+
+impl Fork for SharedFile {
+    fn fork(&self) -> io::Result<Self> {
+        Ok(self.clone())
+    }
+}
+
+/// Plain or block-compressed data, detected by its first bytes
+pub enum DataFile<R> {
+    Plain(BufReader<R>),
+    Bgzf(crate::bgzf::BgzfReader<R>),
+}
+
+impl<R: Read + Seek> DataFile<R> {
+    /// Data compressed as a single stream can't be read with seeks, so it is an error
+    pub fn new(mut reader: R) -> Res<Self> {
+        Ok(match Codec::detect_seek(&mut reader)? {
+            Codec::None => DataFile::Plain(BufReader::new(reader)),
+            Codec::Bgzf => DataFile::Bgzf(crate::bgzf::BgzfReader::new(reader)),
+            codec => return Err(format_err!(
+                "Data is compressed with {:?} and can be read only sequentially. Use bgzf or no compression",
+                codec
+            ))
+        })
+    }
+}
+
+impl<R: Read + Seek> Read for DataFile<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            DataFile::Plain(x) => x.read(buf),
+            DataFile::Bgzf(x) => x.read(buf),
+        }
+    }
+}
+
+impl<R: Read + Seek> Seek for DataFile<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            DataFile::Plain(x) => x.seek(pos),
+            DataFile::Bgzf(x) => x.seek(pos),
+        }
+    }
+
+    fn stream_position(&mut self) -> io::Result<u64> {
+        match self {
+            DataFile::Plain(x) => x.stream_position(),
+            DataFile::Bgzf(x) => x.stream_position(),
+        }
+    }
+}
+
+impl<R: Read + Fork> Fork for DataFile<R> {
+    fn fork(&self) -> io::Result<Self> {
+        Ok(match self {
+            DataFile::Plain(x) => DataFile::Plain(x.fork()?),
+            DataFile::Bgzf(x) => DataFile::Bgzf(x.fork()?),
+        })
+    }
+
+    fn is_block_compressed(&self) -> bool {
+        match self {
+            DataFile::Plain(_) => false,
+            DataFile::Bgzf(_) => true,
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use chrono::{NaiveDateTime, NaiveDate, Datelike, Timelike};
+use streaming_iterator::StreamingIterator;
+use std::collections::HashSet;
+use crate::data::*;
+use crate::Res;
+
+const WEEK_HOURS: usize = 7 * 24;
+
+/// Activity pattern of a user which can be compared with others
+pub struct Fingerprint {
+    /// Share of comments in every hour of week, starting from Monday 00:00
+    hours: Vec<f64>,
+    /// Sorted days with at least one comment
+    days: Vec<NaiveDate>,
+}
+
+impl Fingerprint {
+    pub fn new(points: &[NaiveDateTime]) -> Self {
+        let mut hours = vec![0.0; WEEK_HOURS];
+        let mut days: Vec<NaiveDate> = Vec::new();
+        for p in points {
+            let idx = p.weekday().num_days_from_monday() as usize * 24 + p.hour() as usize;
+            hours[idx] += 1.0;
+            if days.last() != Some(&p.date()) {
+                days.push(p.date());
+            }
+        }
+        if !points.is_empty() {
+            for h in &mut hours {
+                *h /= points.len() as f64;
+            }
+        }
+        days.sort();
+        days.dedup();
+        Fingerprint { hours, days }
+    }
+
+    /// Cosine similarity of hour-of-week histograms
+    pub fn cosine(&self, other: &Self) -> f64 {
+        let dot: f64 = self.hours.iter().zip(&other.hours).map(|(a, b)| a * b).sum();
+        let norm = |x: &[f64]| x.iter().map(|a| a * a).sum::<f64>().sqrt();
+        let norms = norm(&self.hours) * norm(&other.hours);
+        if norms == 0.0 { 0.0 } else { dot / norms }
+    }
+
+    /// Pearson correlation of hour-of-week histograms
+    pub fn correlation(&self, other: &Self) -> f64 {
+        // Empty histograms are all the same distance from the mean, which is not a pattern
+        if self.days.is_empty() || other.days.is_empty() {
+            return 0.0;
+        }
+        let mean = 1.0 / WEEK_HOURS as f64;
+        let (mut cov, mut var_a, mut var_b) = (0.0, 0.0, 0.0);
+        for (a, b) in self.hours.iter().zip(&other.hours) {
+            cov += (a - mean) * (b - mean);
+            var_a += (a - mean) * (a - mean);
+            var_b += (b - mean) * (b - mean);
+        }
+        let norms = (var_a * var_b).sqrt();
+        if norms == 0.0 { 0.0 } else { cov / norms }
+    }
+
+    /// Jaccard index of active days
+    pub fn overlap(&self, other: &Self) -> f64 {
+        let (mut i, mut j, mut common) = (0, 0, 0);
+        while i < self.days.len() && j < other.days.len() {
+            match self.days[i].cmp(&other.days[j]) {
+                std::cmp::Ordering::Less => i += 1,
+                std::cmp::Ordering::Greater => j += 1,
+                std::cmp::Ordering::Equal => {
+                    common += 1;
+                    i += 1;
+                    j += 1;
+                }
+            }
+        }
+        let total = self.days.len() + other.days.len() - common;
+        if total == 0 { 0.0 } else { common as f64 / total as f64 }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Similar {
+    pub user: UserInfo,
+    pub correlation: f64,
+    pub cosine: f64,
+    pub overlap: f64,
+    /// Users are ranked by it
+    pub score: f64,
+}
+
+pub struct SimilarSettings {
+    pub top: usize,
+    /// Users with less comments are too noisy to compare
+    pub min_comments: usize,
+}
+
+/// Ranks every user in data by similarity to `target`. Target users are compared as one
+pub fn find_similar<D: SimpleData>(data: &mut D, target: &[UserInfo], settings: SimilarSettings) -> Res<Vec<Similar>> {
+    let points = crate::join_sorted(target.iter().map(|x| x.comments.iter().cloned()));
+    let fingerprint = Fingerprint::new(&points);
+    let ids: HashSet<i64> = target.iter().map(|x| x.pikabu_id).collect();
+
+    let mut top: Vec<Similar> = Vec::with_capacity(settings.top + 1);
+    let mut reader = data.get_reader(ReadConfig::None)?;
+    while let Some(user) = reader.next() {
+        if ids.contains(&user.pikabu_id) || user.comments.len() < settings.min_comments {
+            continue;
+        }
+        let other = Fingerprint::new(&user.comments);
+        let correlation = fingerprint.correlation(&other);
+        let overlap = fingerprint.overlap(&other);
+        let score = (correlation + overlap) / 2.0;
+        if top.len() == settings.top && top.last().map_or(true, |x| x.score >= score) {
+            continue;
+        }
+        let idx = top.iter().position(|x| x.score < score).unwrap_or(top.len());
+        top.insert(idx, Similar {
+            user: user.clone(),
+            correlation,
+            cosine: fingerprint.cosine(&other),
+            overlap,
+            score
+        });
+        top.truncate(settings.top);
+    }
+    reader.finish()?;
+    Ok(top)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Monday, 10 July 2017
+    fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd(2017, 7, 10 + day).and_hms(hour, minute, 0)
+    }
+
+    fn user(pikabu_id: i64, comments: Vec<NaiveDateTime>) -> UserInfo {
+        UserInfo {
+            name: format!("user{}", pikabu_id),
+            pikabu_id,
+            seek: None,
+            comments,
+            aliases: Vec::new(),
+            streams: Vec::new(),
+            meta: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn empty_user_is_not_similar() {
+        let empty = Fingerprint::new(&[]);
+        let other = Fingerprint::new(&[at(0, 10, 0), at(1, 12, 0)]);
+        for x in &[&empty, &other] {
+            assert_eq!(empty.cosine(x), 0.0);
+            assert_eq!(empty.correlation(x), 0.0);
+            assert_eq!(empty.overlap(x), 0.0);
+        }
+    }
+
+    #[test]
+    fn single_and_identical_comments() {
+        let single = Fingerprint::new(&[at(0, 10, 0)]);
+        // Same second three times is the same pattern as a single comment
+        let same = Fingerprint::new(&[at(0, 10, 5), at(0, 10, 5), at(0, 10, 5)]);
+        assert_eq!(same.days.len(), 1);
+        assert!((single.cosine(&same) - 1.0).abs() < 1e-9);
+        assert!((single.correlation(&same) - 1.0).abs() < 1e-9);
+        assert_eq!(single.overlap(&same), 1.0);
+
+        let later = Fingerprint::new(&[at(1, 11, 0)]);
+        assert_eq!(single.cosine(&later), 0.0);
+        assert!(single.correlation(&later) < 0.0);
+        assert_eq!(single.overlap(&later), 0.0);
+    }
+
+    #[test]
+    fn overlap_of_days() {
+        let a = Fingerprint::new(&[at(0, 10, 0), at(1, 10, 0), at(2, 10, 0)]);
+        let b = Fingerprint::new(&[at(1, 20, 0), at(2, 20, 0), at(3, 20, 0)]);
+        assert_eq!(a.overlap(&b), 0.5);
+        assert_eq!(a.overlap(&b), b.overlap(&a));
+    }
+
+    #[test]
+    fn ranks_users_with_same_pattern_first() {
+        let pattern = |hour| (0..5).map(|day| at(day, hour, 0)).collect::<Vec<_>>();
+        let target = user(1, pattern(10));
+        let mut buf = Vec::new();
+        for x in &[user(1, pattern(10)), user(2, pattern(22)), user(3, pattern(10)), user(4, vec![at(0, 10, 0)]), user(5, Vec::new())] {
+            write_chunk(&mut buf, Some(x)).unwrap();
+        }
+        write_chunk(&mut buf, None).unwrap();
+
+        let mut data: Data<_, CacheRef> = Data::new(&buf[..]);
+        let found = find_similar(&mut data, &[target], SimilarSettings { top: 10, min_comments: 2 }).unwrap();
+        let ids: Vec<i64> = found.iter().map(|x| x.user.pikabu_id).collect();
+        assert_eq!(ids, vec![3, 2]);
+        assert!((found[0].score - 1.0).abs() < 1e-9);
+        // Same days, but other hours
+        assert_eq!(found[1].overlap, 1.0);
+        assert!(found[1].correlation < 0.0);
+    }
+}
@@ -0,0 +1,215 @@
+use chrono::{NaiveDateTime, NaiveDate, Datelike, Timelike, Duration};
+use std::cmp::Ordering;
+use serde::Serialize;
+
+/// Consecutive days, both ends are inclusive
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Period {
+    pub start: NaiveDate,
+    pub end: NaiveDate,
+    pub days: i64,
+}
+
+/// Time without comments between two of them
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Gap {
+    pub start: NaiveDateTime,
+    pub end: NaiveDateTime,
+    pub seconds: i64,
+}
+
+/// Summary of user activity. All times are shifted to the requested timezone
+#[derive(Debug, Clone, Serialize)]
+pub struct UserStats {
+    pub comments: usize,
+    pub first: Option<NaiveDateTime>,
+    pub last: Option<NaiveDateTime>,
+    /// Days with at least one comment
+    pub active_days: usize,
+    pub longest_streak: Option<Period>,
+    pub longest_gap: Option<Gap>,
+    /// Starting from Monday
+    pub weekdays: [usize; 7],
+    pub hours: [usize; 24],
+    /// Most comments written in the same minute
+    pub peak_minute: usize,
+    /// Number of minutes with that many comments
+    pub peak_minutes: usize,
+    pub median_interval: Option<i64>,
+}
+
+impl UserStats {
+    /// Points must be sorted, timezone is an offset in hours
+    pub fn new(points: &[NaiveDateTime], timezone: i8) -> Self {
+        let shift = Duration::hours(timezone.into());
+        let points: Vec<NaiveDateTime> = points.iter().map(|x| *x + shift).collect();
+
+        let mut weekdays = [0; 7];
+        let mut hours = [0; 24];
+        for p in &points {
+            weekdays[p.weekday().num_days_from_monday() as usize] += 1;
+            hours[p.hour() as usize] += 1;
+        }
+
+        let mut days: Vec<NaiveDate> = points.iter().map(|x| x.date()).collect();
+        days.dedup();
+
+        let mut longest_streak: Option<Period> = None;
+        let mut start = 0;
+        for i in 0..days.len() {
+            let is_end = i + 1 == days.len() || days[i + 1] - days[i] != Duration::days(1);
+            if is_end {
+                let period = Period {
+                    start: days[start],
+                    end: days[i],
+                    days: (i - start + 1) as i64
+                };
+                if longest_streak.map_or(true, |x| x.days < period.days) {
+                    longest_streak = Some(period);
+                }
+                start = i + 1;
+            }
+        }
+
+        let mut intervals: Vec<i64> = points.windows(2)
+            .map(|x| (x[1] - x[0]).num_seconds())
+            .collect();
+        let longest_gap = points.windows(2)
+            .max_by_key(|x| x[1] - x[0])
+            .map(|x| Gap {
+                start: x[0],
+                end: x[1],
+                seconds: (x[1] - x[0]).num_seconds()
+            });
+        intervals.sort();
+        let median_interval = match intervals.len() {
+            0 => None,
+            n if n % 2 == 1 => Some(intervals[n / 2]),
+            n => Some((intervals[n / 2 - 1] + intervals[n / 2]) / 2)
+        };
+
+        // Points are sorted, so the same minutes are next to each other
+        let (mut peak_minute, mut peak_minutes) = (0, 0);
+        let minute = |x: &NaiveDateTime| x.timestamp() / 60;
+        let mut i = 0;
+        while i < points.len() {
+            let mut j = i;
+            while j < points.len() && minute(&points[j]) == minute(&points[i]) {
+                j += 1;
+            }
+            let count = j - i;
+            match count.cmp(&peak_minute) {
+                Ordering::Greater => {
+                    peak_minute = count;
+                    peak_minutes = 1;
+                },
+                Ordering::Equal => peak_minutes += 1,
+                Ordering::Less => {},
+            }
+            i = j;
+        }
+
+        UserStats {
+            comments: points.len(),
+            first: points.first().cloned(),
+            last: points.last().cloned(),
+            active_days: days.len(),
+            longest_streak,
+            longest_gap,
+            weekdays,
+            hours,
+            peak_minute,
+            peak_minutes,
+            median_interval,
+        }
+    }
+}
+
+/// Human readable duration like `3d 04:05:06`
+pub fn format_seconds(seconds: i64) -> String {
+    let (days, rest) = (seconds / 86400, seconds % 86400);
+    let time = format!("{:02}:{:02}:{:02}", rest / 3600, rest % 3600 / 60, rest % 60);
+    if days == 0 {
+        time
+    } else {
+        format!("{}d {}", days, time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Monday, 10 July 2017
+    fn at(day: u32, hour: u32, minute: u32, second: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd(2017, 7, 10 + day).and_hms(hour, minute, second)
+    }
+
+    #[test]
+    fn empty_user() {
+        let stats = UserStats::new(&[], 3);
+        assert_eq!(stats.comments, 0);
+        assert_eq!((stats.first, stats.last), (None, None));
+        assert_eq!(stats.active_days, 0);
+        assert!(stats.longest_streak.is_none());
+        assert!(stats.longest_gap.is_none());
+        assert_eq!(stats.median_interval, None);
+        assert_eq!((stats.peak_minute, stats.peak_minutes), (0, 0));
+        assert_eq!(stats.hours.iter().sum::<usize>(), 0);
+    }
+
+    #[test]
+    fn single_comment() {
+        let stats = UserStats::new(&[at(0, 10, 0, 0)], 0);
+        assert_eq!(stats.comments, 1);
+        assert_eq!(stats.first, stats.last);
+        assert_eq!(stats.active_days, 1);
+        assert_eq!(stats.longest_streak.unwrap().days, 1);
+        assert!(stats.longest_gap.is_none());
+        assert_eq!(stats.median_interval, None);
+        assert_eq!((stats.peak_minute, stats.peak_minutes), (1, 1));
+        assert_eq!((stats.weekdays[0], stats.hours[10]), (1, 1));
+    }
+
+    #[test]
+    fn identical_timestamps() {
+        let points = vec![at(0, 10, 0, 5); 3];
+        let stats = UserStats::new(&points, 0);
+        assert_eq!(stats.comments, 3);
+        assert_eq!(stats.active_days, 1);
+        assert_eq!(stats.longest_gap.unwrap().seconds, 0);
+        assert_eq!(stats.median_interval, Some(0));
+        assert_eq!((stats.peak_minute, stats.peak_minutes), (3, 1));
+    }
+
+    #[test]
+    fn aggregates_shifted_to_timezone() {
+        let points = [
+            // Sunday evening in UTC is Monday in UTC+3
+            at(6, 22, 0, 0),
+            at(7, 10, 0, 0), at(7, 10, 0, 30),
+            at(8, 10, 0, 0),
+            at(10, 10, 0, 0), at(10, 10, 1, 0),
+        ];
+        let stats = UserStats::new(&points, 3);
+        assert_eq!(stats.first, Some(at(7, 1, 0, 0)));
+        assert_eq!(stats.last, Some(at(10, 13, 1, 0)));
+        assert_eq!(stats.active_days, 3);
+        assert_eq!(stats.weekdays, [3, 1, 0, 2, 0, 0, 0]);
+        assert_eq!((stats.hours[1], stats.hours[13]), (1, 5));
+
+        let streak = stats.longest_streak.unwrap();
+        assert_eq!((streak.start, streak.end, streak.days), (at(7, 0, 0, 0).date(), at(8, 0, 0, 0).date(), 2));
+        let gap = stats.longest_gap.unwrap();
+        assert_eq!((gap.start, gap.end, gap.seconds), (at(8, 13, 0, 0), at(10, 13, 0, 0), 2 * 86400));
+        // Intervals are 12h, 30s, 1d minus 30s, 2d and 60s
+        assert_eq!(stats.median_interval, Some(12 * 3600));
+        assert_eq!((stats.peak_minute, stats.peak_minutes), (2, 1));
+    }
+
+    #[test]
+    fn formats_seconds() {
+        assert_eq!(format_seconds(0), "00:00:00");
+        assert_eq!(format_seconds(3 * 86400 + 4 * 3600 + 5 * 60 + 6), "3d 04:05:06");
+    }
+}
@@ -0,0 +1,247 @@
+// Checks data files for corrupted records, and writes a copy without them
+use std::collections::HashMap;
+use std::fmt;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use crate::data::*;
+use crate::Res;
+
+/// 2000-01-01. Earlier timestamps are garbage, usually a record without `END`
+const MIN_TIMESTAMP: i64 = 946_684_800;
+
+#[derive(Debug, Clone)]
+pub struct Problem {
+    /// Offset of record, virtual one for block-compressed data
+    pub offset: u64,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.offset, self.message)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// Records which could be read
+    pub records: usize,
+    /// Records skipped by repair: unreadable, broken or duplicated
+    pub skipped: usize,
+    pub problems: Vec<Problem>,
+}
+
+/// Problems of a record which was read. Unsorted events are fixed by repair, other problems are not
+fn check_user(user: &UserInfo, max_timestamp: i64, problems: &mut Vec<String>) -> bool {
+    let mut broken = false;
+    for kind in std::iter::once(COMMENTS).chain(user.streams.iter().map(|x| x.kind.as_str())) {
+        let events = user.events(kind);
+        if events.iter().any(|x| x.timestamp() < MIN_TIMESTAMP || x.timestamp() > max_timestamp) {
+            problems.push(format!("Timestamps of {} are out of range, end of record may be missing", kind));
+            broken = true;
+        }
+        if events.windows(2).any(|x| x[0] > x[1]) {
+            problems.push(format!("Timestamps of {} are not sorted", kind));
+        }
+        if user.meta(kind).len() > events.len() {
+            problems.push(format!("More attributes of {} than events", kind));
+            broken = true;
+        }
+    }
+    broken
+}
+
+/// Offset of the second byte of record. In block-compressed data `pos + 1` may be past the end of block,
+/// so the byte is read and the reader tells where the next one is
+fn after_start<R: Read + Seek>(reader: &mut R, pos: u64) -> Res<Option<u64>> {
+    reader.seek(SeekFrom::Start(pos))?;
+    if reader.read(&mut [0])? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(reader.stream_position()?))
+}
+
+/// Reads every record of data. Unreadable records are skipped by looking for the next one.
+/// Good records are written to `repair` if it is given, so it is a valid data file
+pub fn verify<R: Read + Seek, W: Write>(reader: &mut R, index: Option<&[IndexEntry]>, mut repair: Option<W>) -> Res<VerifyReport> {
+    let mut report = VerifyReport::default();
+    let end = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(0))?;
+    // A day ahead, because of timezones
+    let max_timestamp = chrono::Utc::now().timestamp() + 24 * 60 * 60;
+
+    // Offset of record and its names, for checking index
+    let mut records: HashMap<u64, (i64, Vec<String>)> = HashMap::new();
+    let mut ids: HashMap<i64, u64> = HashMap::new();
+    let mut pos = 0;
+    // Reader stays after the last record, it is moved only to skip an unreadable one
+    loop {
+        let mut messages = Vec::new();
+        let next = match read_chunk(&mut *reader, Some(pos as usize)) {
+            Ok(None) => {
+                let mut rest = [0];
+                if reader.read(&mut rest)? != 0 {
+                    messages.push("Data after terminating record".to_string());
+                }
+                None
+            },
+            Ok(Some(mut user)) => {
+                let next = reader.stream_position()?;
+                report.records += 1;
+                let mut broken = check_user(&user, max_timestamp, &mut messages);
+                if let Some(first) = ids.get(&user.pikabu_id) {
+                    messages.push(format!("Duplicate id {}, first seen at {}", user.pikabu_id, first));
+                    broken = true;
+                } else {
+                    ids.insert(user.pikabu_id, pos);
+                }
+                records.insert(pos, (user.pikabu_id, user.names().map(str::to_lowercase).collect()));
+                if broken {
+                    report.skipped += 1;
+                } else if let Some(w) = &mut repair {
+                    user.sort_events();
+                    write_chunk(&mut *w, Some(&user))?;
+                }
+                Some(next)
+            },
+            Err(_) if pos >= end => {
+                messages.push("File ends without terminating record".to_string());
+                None
+            },
+            Err(e) => {
+                messages.push(format!("Unreadable record: {}", e));
+                report.skipped += 1;
+                let next = match after_start(&mut *reader, pos)? {
+                    Some(from) => resync(&mut *reader, from)?,
+                    None => None
+                };
+                match next {
+                    Some(x) => { reader.seek(SeekFrom::Start(x))?; },
+                    None => messages.push("No records after unreadable one, terminating record is missing".to_string())
+                }
+                next
+            }
+        };
+        report.problems.extend(messages.into_iter().map(|message| Problem { offset: pos, message }));
+        match next {
+            Some(x) => pos = x,
+            None => break
+        }
+    }
+
+    if let Some(index) = index {
+        for (n, entry) in index.iter().enumerate() {
+            let message = match records.get(&(entry.seek as u64)) {
+                None => "is not a start of record".to_string(),
+                Some((id, _)) if *id != entry.pikabu_id => format!("points to id {} instead of {}", id, entry.pikabu_id),
+                Some((_, names)) if !names.contains(&entry.name.to_lowercase()) => format!("has unknown name {}", entry.name),
+                Some(_) => continue
+            };
+            report.problems.push(Problem {
+                offset: entry.seek as u64,
+                message: format!("Line {} of index {}", n + 1, message),
+            });
+        }
+    }
+
+    if let Some(mut w) = repair {
+        write_chunk(&mut w, None)?;
+        w.flush()?;
+    }
+    Ok(report)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use byteorder::{LE, WriteBytesExt};
+    use chrono::NaiveDateTime;
+    use flate2::Compression;
+    use crate::bgzf::BgzfWriter;
+    use crate::shared::DataFile;
+
+    fn user(pikabu_id: i64) -> UserInfo {
+        UserInfo {
+            name: format!("user{}", pikabu_id),
+            pikabu_id,
+            seek: None,
+            // 8 KB, so bgzf blocks hold several records
+            comments: (0..1000).map(|x| NaiveDateTime::from_timestamp(1_500_000_000 + pikabu_id * 10_000 + x, 0)).collect(),
+            aliases: Vec::new(),
+            streams: Vec::new(),
+            meta: Vec::new(),
+        }
+    }
+
+    /// Record with unknown tag instead of its first timestamp
+    fn broken(pikabu_id: i64) -> Vec<u8> {
+        let mut buf = Vec::new();
+        write_chunk(&mut buf, Some(&user(pikabu_id))).unwrap();
+        let at = 8 + format!("user{}", pikabu_id).len() + 1;
+        (&mut buf[at..]).write_i64::<LE>(i64::MIN + 100).unwrap();
+        buf
+    }
+
+    /// Records with ids from 0, where `bad` is broken. Offsets are ones given by `offset`
+    fn write<W: Write>(mut writer: W, count: i64, bad: i64, offset: impl Fn(&W) -> u64) -> (W, Vec<u64>) {
+        let mut offsets = Vec::new();
+        for id in 0..count {
+            offsets.push(offset(&writer));
+            if id == bad {
+                writer.write_all(&broken(id)).unwrap();
+            } else {
+                write_chunk(&mut writer, Some(&user(id))).unwrap();
+            }
+        }
+        write_chunk(&mut writer, None).unwrap();
+        (writer, offsets)
+    }
+
+    fn check(data: Vec<u8>, offsets: &[u64], bad: i64) {
+        let mut reader = DataFile::new(Cursor::new(data)).unwrap();
+        let mut repaired = Vec::new();
+        let report = verify(&mut reader, None, Some(&mut repaired)).unwrap();
+        assert_eq!(report.records, offsets.len() - 1);
+        assert_eq!(report.skipped, 1);
+        assert_eq!(report.problems.len(), 1, "{:?}", report.problems);
+        assert_eq!(report.problems[0].offset, offsets[bad as usize]);
+        assert!(report.problems[0].message.starts_with("Unreadable record"));
+
+        let mut repaired = &repaired[..];
+        let mut ids = Vec::new();
+        while let Some(user) = read_chunk(&mut repaired, None).unwrap() {
+            ids.push(user.pikabu_id);
+        }
+        let expected: Vec<i64> = (0..offsets.len() as i64).filter(|x| *x != bad).collect();
+        assert_eq!(ids, expected);
+    }
+
+    #[test]
+    fn skips_broken_plain_record() {
+        let (data, offsets) = write(Vec::new(), 10, 4, |w| w.len() as u64);
+        check(data, &offsets, 4);
+    }
+
+    #[test]
+    fn skips_broken_bgzf_record() {
+        let bgzf = || BgzfWriter::new(Vec::new(), Compression::fast());
+        // Broken record starts in one block and ends in another, so resync goes through the end of block
+        let (_, offsets) = write(bgzf(), 30, -1, |w| w.virtual_offset());
+        let bad = offsets.windows(2)
+            .position(|x| x[0] >> 16 != x[1] >> 16 && x[1] & 0xffff != 0)
+            .unwrap() as i64;
+        let (writer, offsets) = write(bgzf(), 30, bad, |w| w.virtual_offset());
+        let data = writer.finish().unwrap();
+
+        // End of full block is a valid offset, the next byte is in the next block
+        let mut reader = DataFile::new(Cursor::new(&data[..])).unwrap();
+        let end = offsets[bad as usize] >> 16 << 16 | 0xff00;
+        let next_block = offsets[bad as usize + 1] >> 16 << 16;
+        assert!(reader.seek(SeekFrom::Start(end + 1)).is_err());
+        assert_eq!(after_start(&mut reader, end).unwrap(), Some(next_block | 1));
+
+        check(data, &offsets, bad);
+    }
+}
@@ -1,34 +1,110 @@
-use std::sync::Arc;
-use parking_lot::Mutex;
 use rocket::State;
+use rocket::request::LenientForm;
 use rocket::response::content::Html;
-use crate::pikadots::search::find_seek;
+use crate::pikadots::search::{find_seek, plan_seek};
 use std::io::Cursor;
 use image::DynamicImage;
-use pikadots::search::{UserSelector, SearchSettings};
+use pikadots::search::{UserSelector, SearchSettings, LimitReached};
 use pikadots::join_sorted;
-use pikadots::data::UserInfo;
+use pikadots::data::{UserInfo, COMMENTS};
+use pikadots::draw::{Palette, Chart};
+use chrono::{NaiveDate, NaiveDateTime};
+use pikadots::similar::{find_similar, SimilarSettings};
+use rocket::http::Header;
+use std::sync::Arc;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use images::{ImageCache, Image, IfNoneMatch};
+use reload::{Reload, Served};
+use limit::{Limiter, Client};
+use pikadots::Res;
+
+pub use reload::Loader;
+pub use limit::Limits;
 
-// Only plain data from file is available for WebState. No cache support, too
-// FIXME: Too concrete type: Mutex<BufReader<File>>
+mod api;
+mod cors;
+mod found;
+mod images;
+mod limit;
+mod metrics;
+mod pages;
+mod reload;
+
+// Only data from file is available for WebState. No cache support, too
+// Every request works with its own fork, so this mutex is never contended
+// FIXME: Too concrete type: Mutex<DataFile<SharedFile>>
 pub type Data =
     crate::pikadots::data::Data<
         parking_lot::Mutex<
-            std::io::BufReader<
-                std::fs::File
+            pikadots::shared::DataFile<
+                pikadots::shared::SharedFile
             >
         >,
         crate::pikadots::data::SeekableRef
     >;
 
 struct WebState {
-    data: Arc<Mutex<Data>>,
-    cache: bool,
+    reload: Reload,
+    threads: usize,
+    images: ImageCache,
+    found: found::FoundCache,
+    metrics: metrics::Metrics,
+    limiter: Limiter,
+    /// Mount point without trailing slash, prepended to every link
+    base: String,
+    profile_link: Option<String>,
+}
+
+impl WebState {
+    /// Data as of now. Reload does not affect already taken snapshots
+    fn served(&self) -> Arc<Served> {
+        self.reload.current()
+    }
+}
+
+/// Reloading threads need the state too, so it is shared
+type Shared = Arc<WebState>;
+
+pub struct Settings {
+    pub threads: usize,
+    /// Size of rendered images cache in bytes
+    pub image_cache: usize,
+    /// Mount point, so server can live under a sub-path behind reverse proxy
+    pub base: String,
+    /// External profile link, `{id}` and `{name}` are replaced. Names are not linked without it
+    pub profile_link: Option<String>,
+    /// Origins allowed to make cross-origin requests, `*` allows any
+    pub cors: Vec<String>,
+    /// Token for `POST /reload`. Reloading is disabled without it
+    pub reload_token: Option<String>,
+    /// Files to watch for changes
+    pub watch: Vec<PathBuf>,
+    /// How often to check watched files. Files are not watched without it
+    pub watch_interval: Option<Duration>,
+    pub limits: Limits,
 }
 
+pub const DEFAULT_PROFILE_LINK: &str = "https://pikastat.d3d.info/user/pikabu_id=={id}";
+
 #[derive(Responder)]
-#[response(status = 200, content_type = "image/png")]
-struct Png(Vec<u8>);
+enum Png {
+    #[response(status = 200, content_type = "image/png")]
+    Fresh(Vec<u8>, Header<'static>, Header<'static>),
+    #[response(status = 304)]
+    NotModified((), Header<'static>, Header<'static>),
+}
+
+impl Png {
+    fn new(image: &Image, if_none_match: &IfNoneMatch) -> Self {
+        let (etag, cache_control) = (images::etag(image), images::cache_control());
+        if if_none_match.matches(&image.etag) {
+            Png::NotModified((), etag, cache_control)
+        } else {
+            Png::Fresh(image.png.clone(), etag, cache_control)
+        }
+    }
+}
 
 #[derive(Responder, Debug)]
 enum Error {
@@ -36,34 +112,71 @@ enum Error {
     Inernal(String),
     #[response(status = 400, content_type = "text/plain")]
     InvalidRequest(String),
+    #[response(status = 403, content_type = "text/plain")]
+    Forbidden(&'static str),
     #[response(status = 404, content_type = "text/plain")]
-    NotFound(&'static str)
+    NotFound(&'static str),
+    /// Query will never be allowed
+    #[response(status = 429, content_type = "text/plain")]
+    TooExpensive(String),
+    /// Query can be retried after some time, which is sent in `Retry-After`
+    #[response(status = 429, content_type = "text/plain")]
+    TooManyRequests(String, Header<'static>),
+}
+
+/// Blank value is the same as missing one, forms send it for empty fields
+fn parse_date(date: Option<String>) -> Result<Option<NaiveDate>, Error> {
+    match date.as_ref().map(|x| x.trim()) {
+        None | Some("") => Ok(None),
+        Some(x) => NaiveDate::parse_from_str(x, "%Y-%m-%d")
+            .map(Some)
+            .map_err(|e| Error::InvalidRequest(format!("Invalid date '{}': {}", x, e)))
+    }
+}
+
+/// Data for a single request: own reader, cache shared with all others
+fn fork_data(served: &Served) -> Result<Data, Error> {
+    served.data.fork()
+        .map_err(|e| {
+            Error::Inernal(format!("Unable to open data: {:?}", e))
+        })
 }
 
-fn find_user(state: State<WebState>, query: String) -> Result<Vec<UserInfo>, Error> {
+/// Recently found users are reused without charging the client again
+fn find_user(state: &WebState, client: &Client, query: String) -> Result<Vec<UserInfo>, Error> {
+    // Generation is taken before data, as for images
+    let key = format!("{}:{}", state.reload.generation(), query);
+    if let Some(x) = state.found.get(&key) {
+        return Ok(x.to_vec());
+    }
     let query: Result<Vec<_>, _> = query.split(',').map(UserSelector::new).collect();
     let query = query.map_err(|e| {
         Error::InvalidRequest(format!("Invalid selector: {}", e))
     })?;
 
     let mut res = {
-        // Block for mutex
-
-        let mut data = state.data.try_lock()
-            .ok_or_else(|| {
-                Error::Inernal("Unable to acquire mutex. Try restarting server ¯\\_(ツ)_/¯".to_string())
-            })?;
+        let served = state.served();
+        let mut data = fork_data(&served)?;
         let query = vec![query];
-        find_seek(&mut *data, query,  SearchSettings{
-            use_cache: state.cache,
-            limit: 100
-        })
-            .map_err(|e| {
-                Error::Inernal(format!("Error searching this user: {:?}", e))
+        let settings = SearchSettings{
+            use_cache: served.cache,
+            limit: 100,
+            threads: state.threads
+        };
+        let plan = plan_seek(&data, &query, &settings);
+        state.metrics.plan(&plan);
+        let _permit = state.limiter.check(client, limit::cost(&plan), plan.full_scan())?;
+        find_seek(&mut data, query, settings)
+            .map_err(|e| match e.downcast::<LimitReached>() {
+                Ok(_) => Error::InvalidRequest(
+                    "Too many users match this query. Use a narrower one".to_string()
+                ),
+                Err(e) => Error::Inernal(format!("Error searching this user: {:?}", e))
             })?
     };
     let user = res.pop();
     if let Some(x) = user {
+        state.found.put(key, Arc::new(x.clone()));
         Ok(x)
     } else {
         Err(Error::NotFound("No such user"))
@@ -71,8 +184,126 @@ fn find_user(state: State<WebState>, query: String) -> Result<Vec<UserInfo>, Err
 }
 
 #[get("/<query>/i.html")]
-fn do_info(state: State<WebState>, query: String) -> Result<Html<String>, Error> {
-    let users = find_user(state, query)?;
+fn do_info(state: State<Shared>, client: Client, query: String) -> Result<Html<String>, Error> {
+    let users = find_user(&state, &client, query)?;
+    Ok(Html(pages::page("PikaDots", &pages::users_table(&state, &users))))
+}
+
+/// Same users and options give the same key regardless of selector order and case
+fn image_key(
+    query: &str, chart: Chart, tz: i8, palette: Palette, from: Option<NaiveDate>, to: Option<NaiveDate>, kinds: &[String]
+) -> Result<String, Error> {
+    let selectors: Result<Vec<_>, _> = query.split(',')
+        .map(|x| UserSelector::new(x).map(|x| x.human_readable()))
+        .collect();
+    let mut selectors = selectors.map_err(|e| {
+        Error::InvalidRequest(format!("Invalid selector: {}", e))
+    })?;
+    selectors.sort();
+    selectors.dedup();
+    Ok(format!(
+        "{}/{:?}?tz={}&palette={:?}&from={:?}&to={:?}&kind={}",
+        selectors.join(","), chart, tz, palette, from, to, kinds.join(",")
+    ))
+}
+
+/// Query of image routes. Dates are inclusive and formatted as `YYYY-MM-DD`.
+/// Several comma separated kinds are overlaid
+#[derive(FromForm)]
+struct ImageOptions {
+    tz: Option<i8>,
+    palette: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    kind: Option<String>,
+}
+
+fn render(
+    state: &WebState, client: &Client, if_none_match: &IfNoneMatch, chart: Chart, query: String, opts: &ImageOptions
+) -> Result<Png, Error> {
+    let tz = opts.tz.unwrap_or(0);
+    let palette = match &opts.palette {
+        Some(x) => x.parse().map_err(|e| Error::InvalidRequest(format!("{}", e)))?,
+        None => Palette::default()
+    };
+    let (from, to) = (parse_date(opts.from.clone())?, parse_date(opts.to.clone())?);
+    let kinds = pikadots::data::parse_kinds(opts.kind.as_ref().map_or(COMMENTS, String::as_str))
+        .map_err(|e| Error::InvalidRequest(format!("{}", e)))?;
+    if chart == Chart::Dots && kinds.len() > pikadots::draw::MAX_LAYERS {
+        return Err(Error::InvalidRequest(format!("At most {} kinds can be overlaid", pikadots::draw::MAX_LAYERS)));
+    }
+    // Images of older data are never hit. Generation is taken before data, so an image
+    // of older data can't be put under the key of newer one
+    let key = format!("{}:{}", state.reload.generation(), image_key(&query, chart, tz, palette, from, to, &kinds)?);
+    if let Some(image) = state.images.get(&key) {
+        return Ok(Png::new(&image, if_none_match));
+    }
+    let users = find_user(state, client, query)?;
+
+    let started = Instant::now();
+    let buf = Vec::new();
+    let mut writer = Cursor::new(buf);
+    let in_range = |x: &NaiveDateTime| {
+        from.map_or(true, |f| x.date() >= f) && to.map_or(true, |t| x.date() <= t)
+    };
+    let layers: Vec<_> = kinds.iter()
+        .map(|kind| join_sorted(users.iter().map(|x| {
+            x.events(kind).iter().cloned().filter(in_range)
+        })))
+        .collect();
+    let img = pikadots::draw::draw_layers(&layers, chart, tz, palette)
+        .map_err(|e| {
+            Error::Inernal(format!("Error saving image: {:?}", e))
+        })?;
+    DynamicImage::ImageRgb8(img).write_to(&mut writer, image::PNG)
+        .map_err(|e| {
+            Error::Inernal(format!("Error writing image: {:?}", e))
+        })?;
+
+    state.metrics.render(started.elapsed());
+    let image = Arc::new(Image::new(writer.into_inner()));
+    state.images.put(key, image.clone());
+    Ok(Png::new(&image, if_none_match))
+}
+
+#[get("/<query>/i.png?<opts..>")]
+fn do_draw(
+    state: State<Shared>, client: Client, if_none_match: IfNoneMatch, query: String, opts: LenientForm<ImageOptions>
+) -> Result<Png, Error> {
+    render(&state, &client, &if_none_match, Chart::Dots, query, &opts)
+}
+
+/// Comments by weekday and hour
+#[get("/<query>/weekhour.png?<opts..>")]
+fn do_weekhour(
+    state: State<Shared>, client: Client, if_none_match: IfNoneMatch, query: String, opts: LenientForm<ImageOptions>
+) -> Result<Png, Error> {
+    render(&state, &client, &if_none_match, Chart::WeekHour, query, &opts)
+}
+
+/// Comments by hour of day
+#[get("/<query>/hourly.png?<opts..>")]
+fn do_hourly(
+    state: State<Shared>, client: Client, if_none_match: IfNoneMatch, query: String, opts: LenientForm<ImageOptions>
+) -> Result<Png, Error> {
+    render(&state, &client, &if_none_match, Chart::Hourly, query, &opts)
+}
+
+#[get("/<query>/similar.html?<top>")]
+fn do_similar(state: State<Shared>, client: Client, query: String, top: Option<usize>) -> Result<Html<String>, Error> {
+    let target = find_user(&state, &client, query)?;
+    let found = {
+        // Every user is compared with the target
+        let _permit = state.limiter.check(&client, limit::FULL_SCAN_COST, true)?;
+        let mut data = fork_data(&state.served())?;
+        find_similar(&mut data, &target, SimilarSettings {
+            top: top.unwrap_or(20).min(100),
+            min_comments: 10
+        })
+            .map_err(|e| {
+                Error::Inernal(format!("Error searching similar users: {:?}", e))
+            })?
+    };
     let mut res = r#"<!DOCTYPE html>
     <html>
     <head>
@@ -83,74 +314,177 @@ fn do_info(state: State<WebState>, query: String) -> Result<Html<String>, Error>
             <tr>
                 <th>Name</th>
                 <th>Id</th>
-                <th>Sk</th>
+                <th>Score</th>
+                <th>Correlation</th>
+                <th>Cosine</th>
+                <th>Days overlap</th>
                 <th>Comment count</th>
             </tr>
         </thead>
         <tbody>
     "#.to_string();
-    for u in users {
+    for i in found {
         res.push_str(&format!(r#"
             <tr>
-                <td><a href="https://pikastat.d3d.info/user/pikabu_id=={pik}">{name}</a></td>
+                <td><a href="{base}/user.html?q=id:{pik}">{name}</a></td>
                 <td>{pik}</td>
-                <td>{sk}</td>
+                <td align="right">{score:.3}</td>
+                <td align="right">{correlation:.3}</td>
+                <td align="right">{cosine:.3}</td>
+                <td align="right">{overlap:.3}</td>
                 <td align="right">{cnt}</td>
             </tr>"#,
-            pik=u.pikabu_id, name=u.name, cnt=u.comments.len(),
-            sk=u.seek.map(|x| x.to_string()).unwrap_or_default()
+            base=pages::escape(&state.base), pik=i.user.pikabu_id, name=pages::escape(&i.user.name), cnt=i.user.comments.len(),
+            score=i.score, correlation=i.correlation, cosine=i.cosine, overlap=i.overlap
         ))
     }
     res.push_str("</tbody></table></body></html>");
     Ok(Html(res))
 }
 
-#[get("/<query>/i.png?<tz>")]
-fn do_draw(state: State<WebState>, query: String, tz: Option<i8>) -> Result<Png, Error> {
-    let tz = tz.unwrap_or(0);
-    let users = find_user(state, query)?;
+#[get("/stats.txt")]
+fn stats(state: State<Shared>) -> String {
+    let served = state.served();
+    let cache = &served.data.cache;
+    let images = state.images.stats();
+    format!(
+        r#"Stats:
+Cache: {} items
+NameMap: {} items
+IdMap: {} items
+Image cache: {} items, {}/{} bytes
+Image cache hits: {}
+Image cache misses: {}
+Reloads: {}
+Last reload error: {}"#,
+        cache.cached.len(), cache.names.len(), cache.ids.len(),
+        images.items, images.bytes, images.capacity,
+        images.hits, images.misses,
+        state.reload.generation(),
+        state.reload.last_error().unwrap_or_else(|| "none".to_string())
+    )
+}
 
-    let buf = Vec::new();
-    let mut writer = Cursor::new(buf);
-    let points = join_sorted(users.into_iter().map(|x| x.comments));
-    let image = pikadots::draw::generate(&points[..]);
-    let img = image.into_image(tz)
-        .map_err(|e| {
-            Error::Inernal(format!("Error saving image: {:?}", e))
-        })?;
-    DynamicImage::ImageRgb8(img).write_to(&mut writer, image::PNG)
-        .map_err(|e| {
-            Error::Inernal(format!("Error writing image: {:?}", e))
-        })?;
+/// Server with every route, ready to launch. Loader is called again on every reload
+fn build(loader: Loader, settings: Settings) -> Res<rocket::Rocket> {
+    let state: Shared = Arc::new(WebState {
+        reload: Reload::new(loader, settings.reload_token)?,
+        threads: settings.threads,
+        images: ImageCache::new(settings.image_cache),
+        found: Default::default(),
+        metrics: Default::default(),
+        limiter: Limiter::new(settings.limits),
+        base: settings.base.trim_end_matches('/').to_string(),
+        profile_link: settings.profile_link,
+    });
+    if let Some(interval) = settings.watch_interval {
+        reload::watch(state.clone(), settings.watch, interval);
+    }
+    let base = if state.base.is_empty() { "/" } else { &state.base };
+    Ok(rocket::ignite()
+        .mount(base, routes![
+            pages::index, pages::user, pages::stats,
+            do_info, do_draw, do_weekhour, do_hourly, do_similar, stats,
+            api::users, api::names, api::comments, api::user_stats, api::stats,
+            reload::reload, metrics::metrics
+        ])
+        .attach(metrics::RequestTimer)
+        .attach(cors::Cors::new(settings.cors, &state.base))
+        .manage(state))
+}
 
-    Ok(Png(writer.into_inner()))
+/// Loads data and starts server
+pub fn launch(loader: Loader, settings: Settings) -> Res<()> {
+    build(loader, settings)?.launch();
+    Ok(())
 }
 
-#[get("/stats.txt")]
-fn stats(state: State<WebState>) -> String {
-    match state.data.try_lock() {
-        None => {
-            eprintln!("Failed try_lock!");
-            "Unable to acquire mutex. Try restarting server ¯\\_(ツ)_/¯".to_string()
-        },
-        Some(data) => {
-            format!(
-                r#"Stats:
-Cache: {} items
-NameMap: {} items
-IdMap: {} items"#,
-                data.cached.len(), data.names.len(), data.ids.len()
-            )
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::http::Status;
+    use rocket::local::Client as LocalClient;
+    use pikadots::data::write_chunk;
+    use pikadots::shared::{DataFile, SharedFile};
+
+    const REQUESTS: usize = 16;
+
+    fn user(pikabu_id: i64, name: &str) -> UserInfo {
+        UserInfo {
+            name: name.to_string(),
+            pikabu_id,
+            seek: None,
+            comments: (0..100).map(|x| NaiveDateTime::from_timestamp(1_500_000_000 + x * 4321 * pikabu_id, 0)).collect(),
+            aliases: Vec::new(),
+            streams: Vec::new(),
+            meta: Vec::new(),
         }
     }
-}
 
-pub fn launch(data: Data, cache: bool, base: &str) {
-    rocket::ignite()
-        .mount(base, routes![do_info, do_draw, stats])
-        .manage(WebState {
-            data: Arc::new(Mutex::new(data)),
-            cache
-        })
-        .launch();
-}
\ No newline at end of file
+    fn server(path: PathBuf) -> LocalClient {
+        let loader: Loader = Box::new(move || {
+            let data = DataFile::new(SharedFile::open(&path)?)?;
+            Ok((Data::new(parking_lot::Mutex::new(data)), false))
+        });
+        let rocket = build(loader, Settings {
+            threads: 2,
+            // Every request reads the file and renders
+            image_cache: 0,
+            base: String::new(),
+            profile_link: None,
+            cors: Vec::new(),
+            reload_token: None,
+            watch: Vec::new(),
+            watch_interval: None,
+            limits: Limits::default(),
+        }).unwrap();
+        LocalClient::untracked(rocket).unwrap()
+    }
+
+    /// Writes `user0`, `user1`... to a temporary file
+    fn write_users(test: &str, count: usize) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("pikadots-{}-{}.dat", test, std::process::id()));
+        let mut buf = Vec::new();
+        for id in 0..count {
+            write_chunk(&mut buf, Some(&user(id as i64 + 1, &format!("user{}", id)))).unwrap();
+        }
+        write_chunk(&mut buf, None).unwrap();
+        std::fs::write(&path, buf).unwrap();
+        path
+    }
+
+    #[test]
+    fn concurrent_images() {
+        let path = write_users("web", REQUESTS);
+        let client = server(path.clone());
+        let get = |id: usize| {
+            let mut resp = client.get(format!("/user{}/i.png", id)).dispatch();
+            assert_eq!(resp.status(), Status::Ok);
+            resp.body_bytes().unwrap()
+        };
+        // Every request searches its own user, so they share only forks of the file
+        let bodies: Vec<Vec<u8>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..REQUESTS)
+                .map(|id| scope.spawn(move || get(id)))
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        let sequential: Vec<Vec<u8>> = (0..REQUESTS).map(get).collect();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(bodies, sequential);
+        assert!(bodies.windows(2).all(|x| !x[0].is_empty() && x[0] != x[1]));
+    }
+
+    #[test]
+    fn too_many_users_is_bad_request() {
+        let path = write_users("web-limit", 120);
+        let client = server(path.clone());
+        let mut resp = client.get("/re:user.*/i.html").dispatch();
+        let body = resp.body_string().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(resp.status(), Status::BadRequest);
+        assert!(body.contains("narrower"));
+    }
+}
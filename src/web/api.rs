@@ -0,0 +1,98 @@
+use rocket::State;
+use rocket::response::content::Json;
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use pikadots::data::{UserInfo, Alias};
+use pikadots::stats::UserStats;
+use super::{Shared, Error, Client, find_user, parse_date};
+
+#[derive(Serialize)]
+struct UserSummary<'a> {
+    id: i64,
+    name: &'a str,
+    seek: Option<usize>,
+    comments: usize,
+    first: Option<NaiveDateTime>,
+    last: Option<NaiveDateTime>,
+    aliases: &'a [Alias],
+}
+
+impl<'a> From<&'a UserInfo> for UserSummary<'a> {
+    fn from(u: &'a UserInfo) -> Self {
+        UserSummary {
+            id: u.pikabu_id,
+            name: &u.name,
+            seek: u.seek,
+            comments: u.comments.len(),
+            first: u.comments.first().cloned(),
+            last: u.comments.last().cloned(),
+            aliases: &u.aliases,
+        }
+    }
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<Json<String>, Error> {
+    serde_json::to_string(value)
+        .map(Json)
+        .map_err(|e| Error::Inernal(format!("Error serializing response: {:?}", e)))
+}
+
+#[get("/api/users?<q>")]
+pub fn users(state: State<Shared>, client: Client, q: String) -> Result<Json<String>, Error> {
+    let users = find_user(&state, &client, q)?;
+    let summaries: Vec<UserSummary> = users.iter().map(UserSummary::from).collect();
+    to_json(&summaries)
+}
+
+/// Names starting with prefix, for autocomplete
+#[get("/api/names?<prefix>")]
+pub fn names(state: State<Shared>, prefix: String) -> Result<Json<String>, Error> {
+    let prefix = prefix.to_lowercase();
+    let served = state.served();
+    let start = served.names.binary_search(&prefix).unwrap_or_else(|x| x);
+    let found: Vec<&String> = served.names[start..].iter()
+        .take_while(|x| x.starts_with(&prefix))
+        .take(20)
+        .collect();
+    to_json(&found)
+}
+
+/// Dates are inclusive and formatted as `YYYY-MM-DD`
+#[get("/api/users/<id>/comments?<from>&<to>")]
+pub fn comments(state: State<Shared>, client: Client, id: i64, from: Option<String>, to: Option<String>) -> Result<Json<String>, Error> {
+    let (from, to) = (parse_date(from)?, parse_date(to)?);
+    let mut user = find_user(&state, &client, format!("id:{}", id))?
+        .pop()
+        .ok_or(Error::NotFound("No such user"))?;
+    user.retain_events(|x, _| {
+        from.map_or(true, |f| x.date() >= f) && to.map_or(true, |t| x.date() <= t)
+    });
+    to_json(&user)
+}
+
+/// Times are shifted by `tz` hours
+#[get("/api/users/<id>/stats?<tz>")]
+pub fn user_stats(state: State<Shared>, client: Client, id: i64, tz: Option<i8>) -> Result<Json<String>, Error> {
+    let user = find_user(&state, &client, format!("id:{}", id))?
+        .pop()
+        .ok_or(Error::NotFound("No such user"))?;
+    to_json(&UserStats::new(&user.comments, tz.unwrap_or(0)))
+}
+
+#[derive(Serialize)]
+struct Stats {
+    cache: usize,
+    names: usize,
+    ids: usize,
+}
+
+#[get("/api/stats")]
+pub fn stats(state: State<Shared>) -> Result<Json<String>, Error> {
+    let served = state.served();
+    let cache = &served.data.cache;
+    to_json(&Stats {
+        cache: cache.cached.len(),
+        names: cache.names.len(),
+        ids: cache.ids.len(),
+    })
+}
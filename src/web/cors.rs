@@ -0,0 +1,63 @@
+use rocket::{Request, Response};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Header, Method, Status};
+
+/// Allows listed origins to read responses
+pub struct Cors {
+    origins: Vec<String>,
+    /// Path prefix of json API. Preflight requests are answered only there
+    api: String,
+}
+
+impl Cors {
+    /// `base` is mount point without trailing slash
+    pub fn new(origins: Vec<String>, base: &str) -> Self {
+        Cors {
+            origins,
+            api: format!("{}/api/", base),
+        }
+    }
+}
+
+impl Fairing for Cors {
+    fn info(&self) -> Info {
+        Info {
+            name: "CORS",
+            kind: Kind::Response
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        if self.origins.is_empty() {
+            return;
+        }
+        // Headers below depend on Origin, so caches must not share responses between origins,
+        // including requests without it
+        response.adjoin_header(Header::new("Vary", "Origin"));
+        let origin = match request.headers().get_one("Origin") {
+            Some(x) => x,
+            None => return
+        };
+        if self.origins.iter().any(|x| x == "*") {
+            response.set_header(Header::new("Access-Control-Allow-Origin", "*"));
+        } else if self.origins.iter().any(|x| x == origin) {
+            response.set_header(Header::new("Access-Control-Allow-Origin", origin.to_string()));
+        } else {
+            return;
+        }
+
+        // There are no OPTIONS routes, so preflight is answered here instead of 404
+        let preflight = request.method() == Method::Options
+            && request.headers().contains("Access-Control-Request-Method");
+        if preflight && request.uri().path().starts_with(&self.api) {
+            response.set_status(Status::NoContent);
+            response.take_body();
+            response.remove_header("Content-Type");
+            response.set_header(Header::new("Access-Control-Allow-Methods", "GET"));
+            if let Some(headers) = request.headers().get_one("Access-Control-Request-Headers") {
+                response.set_header(Header::new("Access-Control-Allow-Headers", headers.to_string()));
+            }
+            response.set_header(Header::new("Access-Control-Max-Age", "86400"));
+        }
+    }
+}
@@ -0,0 +1,38 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use parking_lot::Mutex;
+use pikadots::data::UserInfo;
+
+/// User page is followed by requests of its images with the same query
+const TTL: Duration = Duration::from_secs(60);
+const MAX_ENTRIES: usize = 256;
+
+/// Users found by recent queries, so a page and its images search and pay for the query once
+type Entry = (Instant, Arc<Vec<UserInfo>>);
+
+#[derive(Default)]
+pub struct FoundCache {
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl FoundCache {
+    pub fn get(&self, key: &str) -> Option<Arc<Vec<UserInfo>>> {
+        match self.entries.lock().get(key) {
+            Some((added, users)) if added.elapsed() < TTL => Some(users.clone()),
+            _ => None
+        }
+    }
+
+    pub fn put(&self, key: String, users: Arc<Vec<UserInfo>>) {
+        let mut entries = self.entries.lock();
+        if entries.len() >= MAX_ENTRIES {
+            entries.retain(|_, (added, _)| added.elapsed() < TTL);
+        }
+        if entries.len() >= MAX_ENTRIES {
+            // Only under heavy load, and then most of them will not be asked again anyway
+            entries.clear();
+        }
+        entries.insert(key, (Instant::now(), users));
+    }
+}
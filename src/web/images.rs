@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use parking_lot::{Mutex, MutexGuard};
+use rocket::request::{self, FromRequest, Request};
+use rocket::Outcome;
+use rocket::http::Header;
+
+/// Rendered png with its ETag
+pub struct Image {
+    pub png: Vec<u8>,
+    pub etag: String,
+}
+
+impl Image {
+    pub fn new(png: Vec<u8>) -> Self {
+        let mut hasher = DefaultHasher::new();
+        png.hash(&mut hasher);
+        Image {
+            etag: format!("\"{:016x}\"", hasher.finish()),
+            png
+        }
+    }
+}
+
+struct Entry {
+    image: Arc<Image>,
+    used: u64,
+}
+
+struct Lru {
+    entries: HashMap<String, Entry>,
+    bytes: usize,
+    clock: u64,
+}
+
+/// LRU cache of rendered images, bounded by total png size
+pub struct ImageCache {
+    lru: Mutex<Lru>,
+    capacity: usize,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    contended: AtomicUsize,
+}
+
+pub struct CacheStats {
+    pub items: usize,
+    pub bytes: usize,
+    pub capacity: usize,
+    pub hits: usize,
+    pub misses: usize,
+    /// Times the cache was locked by another thread
+    pub contended: usize,
+}
+
+impl ImageCache {
+    /// Capacity is in bytes. Zero disables caching
+    pub fn new(capacity: usize) -> Self {
+        ImageCache {
+            lru: Mutex::new(Lru {
+                entries: HashMap::new(),
+                bytes: 0,
+                clock: 0
+            }),
+            capacity,
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+            contended: AtomicUsize::new(0),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<Lru> {
+        match self.lru.try_lock() {
+            Some(x) => x,
+            None => {
+                self.contended.fetch_add(1, Ordering::Relaxed);
+                self.lru.lock()
+            }
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<Arc<Image>> {
+        let mut lru = self.lock();
+        lru.clock += 1;
+        let clock = lru.clock;
+        let res = lru.entries.get_mut(key).map(|x| {
+            x.used = clock;
+            x.image.clone()
+        });
+        drop(lru);
+        let counter = if res.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        res
+    }
+
+    pub fn put(&self, key: String, image: Arc<Image>) {
+        let size = image.png.len();
+        if size > self.capacity {
+            return;
+        }
+        let mut lru = self.lock();
+        lru.clock += 1;
+        let used = lru.clock;
+        if let Some(old) = lru.entries.insert(key, Entry { image, used }) {
+            lru.bytes -= old.image.png.len();
+        }
+        lru.bytes += size;
+        while lru.bytes > self.capacity {
+            // Linear search is fine: cache holds at most few thousands of images
+            let oldest = lru.entries.iter()
+                .min_by_key(|(_, x)| x.used)
+                .map(|(k, _)| k.clone());
+            match oldest {
+                Some(k) => {
+                    let old = lru.entries.remove(&k).unwrap();
+                    lru.bytes -= old.image.png.len();
+                },
+                None => break
+            }
+        }
+    }
+
+    pub fn clear(&self) {
+        let mut lru = self.lock();
+        lru.entries.clear();
+        lru.bytes = 0;
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let lru = self.lock();
+        CacheStats {
+            items: lru.entries.len(),
+            bytes: lru.bytes,
+            capacity: self.capacity,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            contended: self.contended.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Value of `If-None-Match` header
+pub struct IfNoneMatch(Option<String>);
+
+impl IfNoneMatch {
+    pub fn matches(&self, etag: &str) -> bool {
+        match &self.0 {
+            None => false,
+            Some(x) => x.split(',').map(str::trim).any(|x| x == etag || x == "*")
+        }
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for IfNoneMatch {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        Outcome::Success(IfNoneMatch(
+            request.headers().get_one("If-None-Match").map(str::to_string)
+        ))
+    }
+}
+
+pub fn etag(image: &Image) -> Header<'static> {
+    Header::new("ETag", image.etag.clone())
+}
+
+pub fn cache_control() -> Header<'static> {
+    Header::new("Cache-Control", "public, max-age=3600")
+}
@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use parking_lot::Mutex;
+use rocket::request::{self, FromRequest, Request};
+use rocket::{Outcome, State};
+use rocket::http::Header;
+use pikadots::search::{Access, Plan, UserSelector};
+use super::{Error, Shared};
+
+/// Cost of a single lookup in a map
+const LOOKUP_COST: u64 = 1;
+/// Cost of matching every name in names map
+const NAME_SCAN_COST: u64 = 10;
+/// Cost of reading the whole data file
+pub const FULL_SCAN_COST: u64 = 100;
+/// Buckets of least recently seen clients are dropped when there are more
+const MAX_CLIENTS: usize = 10_000;
+/// How often buckets of idle clients are dropped
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Everything is disabled by default
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limits {
+    /// Cost units restored per second for every client
+    pub rate: Option<f64>,
+    /// Cost units client can spend at once. Defaults to 10 full scans
+    pub burst: Option<f64>,
+    /// Queries costing more are rejected
+    pub max_cost: Option<u64>,
+    /// Number of full scans running at the same time
+    pub max_scans: Option<usize>,
+    /// Clients are told apart by `X-Real-IP`. Only for servers behind reverse proxy setting it,
+    /// otherwise anyone can send any address
+    pub trust_proxy: bool,
+}
+
+/// Rough measure of how slow regexp or glob matching is. Plain names are 1
+fn complexity(selector: &UserSelector) -> u64 {
+    let pattern = match selector {
+        UserSelector::Regexp(x) | UserSelector::Glob(x) => x,
+        _ => return 1
+    };
+    let operators = pattern.chars().filter(|x| "*+?{|".contains(*x)).count();
+    1 + pattern.len() as u64 / 16 + operators as u64
+}
+
+/// Estimated amount of work needed to find users by the plan
+pub fn cost(plan: &Plan) -> u64 {
+    plan.steps.iter()
+        .map(|(selector, access)| match access {
+            Access::NameMap | Access::IdMap | Access::Offset => LOOKUP_COST,
+            Access::NameScan => NAME_SCAN_COST * complexity(selector),
+            Access::FullScan => FULL_SCAN_COST * complexity(selector),
+        })
+        .sum()
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+struct Buckets {
+    clients: HashMap<IpAddr, Bucket>,
+    swept: Instant,
+}
+
+pub struct Limiter {
+    limits: Limits,
+    buckets: Mutex<Buckets>,
+    scans: AtomicUsize,
+    max_clients: usize,
+}
+
+/// Allows one full scan while alive
+pub struct ScanPermit<'a>(&'a AtomicUsize);
+
+impl<'a> Drop for ScanPermit<'a> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn too_many(message: String, retry_after: u64) -> Error {
+    Error::TooManyRequests(message, Header::new("Retry-After", retry_after.to_string()))
+}
+
+impl Limiter {
+    pub fn new(limits: Limits) -> Self {
+        Limiter {
+            limits,
+            buckets: Mutex::new(Buckets { clients: HashMap::new(), swept: Instant::now() }),
+            scans: AtomicUsize::new(0),
+            max_clients: MAX_CLIENTS,
+        }
+    }
+
+    fn burst(&self) -> f64 {
+        self.limits.burst.unwrap_or(10.0 * FULL_SCAN_COST as f64)
+    }
+
+    /// Takes cost from client's budget
+    fn spend(&self, client: &Client, cost: u64) -> Result<(), Error> {
+        let (rate, ip) = match (self.limits.rate, client.0) {
+            (Some(rate), Some(ip)) => (rate, ip),
+            _ => return Ok(())
+        };
+        let burst = self.burst();
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock();
+        if now.duration_since(buckets.swept) >= SWEEP_INTERVAL || buckets.clients.len() >= self.max_clients {
+            buckets.swept = now;
+            // Clients with full buckets are the same as unknown ones
+            buckets.clients.retain(|_, x| x.tokens + now.duration_since(x.updated).as_secs_f64() * rate < burst);
+            if buckets.clients.len() >= self.max_clients {
+                // Too many active clients. Forgetting some only gives them a full bucket again,
+                // so least recently seen are dropped to keep room for a while
+                let mut updated: Vec<Instant> = buckets.clients.values().map(|x| x.updated).collect();
+                let keep = self.max_clients * 9 / 10;
+                let (_, &mut oldest_kept, _) = updated.select_nth_unstable_by(keep, |a, b| b.cmp(a));
+                buckets.clients.retain(|_, x| x.updated > oldest_kept);
+            }
+        }
+        let bucket = buckets.clients.entry(ip).or_insert(Bucket { tokens: burst, updated: now });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate).min(burst);
+        bucket.updated = now;
+        let cost = cost as f64;
+        if cost > bucket.tokens {
+            let wait = ((cost.min(burst) - bucket.tokens) / rate).ceil() as u64;
+            return Err(too_many(format!("Rate limit exceeded. Try again in {} seconds", wait), wait));
+        }
+        bucket.tokens -= cost;
+        Ok(())
+    }
+
+    /// Checks whether query can be run now. Permit must be kept until search is done
+    pub fn check(&self, client: &Client, cost: u64, full_scan: bool) -> Result<Option<ScanPermit>, Error> {
+        if let Some(max) = self.limits.max_cost {
+            if cost > max {
+                return Err(Error::TooExpensive(format!(
+                    "Query is too expensive: costs {}, but at most {} is allowed. \
+                    Use plain names or ids instead of regexps and globs",
+                    cost, max
+                )));
+            }
+        }
+        let permit = match (full_scan, self.limits.max_scans) {
+            (true, Some(max)) => {
+                if self.scans.fetch_add(1, Ordering::SeqCst) >= max {
+                    self.scans.fetch_sub(1, Ordering::SeqCst);
+                    return Err(too_many("Too many slow queries are running. Try again later".to_string(), 5));
+                }
+                Some(ScanPermit(&self.scans))
+            },
+            _ => None
+        };
+        self.spend(client, cost)?;
+        Ok(permit)
+    }
+}
+
+/// Address of client. `X-Real-IP` is used only when proxy is trusted
+pub struct Client(Option<IpAddr>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for Client {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        let trust_proxy = request.guard::<State<Shared>>().succeeded()
+            .map_or(false, |x| x.limiter.limits.trust_proxy);
+        let ip = if trust_proxy {
+            request.client_ip()
+        } else {
+            request.remote().map(|x| x.ip())
+        };
+        Outcome::Success(Client(ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn client(n: u32) -> Client {
+        Client(Some(IpAddr::V4(Ipv4Addr::from(n))))
+    }
+
+    #[test]
+    fn buckets_are_bounded() {
+        let mut limiter = Limiter::new(Limits { rate: Some(1.0), burst: Some(10.0), ..Limits::default() });
+        limiter.max_clients = 100;
+        for n in 0..1000 {
+            limiter.spend(&client(n), 5).unwrap();
+            assert!(limiter.buckets.lock().clients.len() <= 100);
+        }
+        // Recently seen clients are kept
+        assert!(limiter.buckets.lock().clients.contains_key(&client(999).0.unwrap()));
+        assert!(limiter.spend(&client(999), 10).is_err());
+    }
+
+    #[test]
+    fn idle_buckets_are_swept() {
+        let limiter = Limiter::new(Limits { rate: Some(1.0), burst: Some(10.0), ..Limits::default() });
+        for n in 0..10 {
+            limiter.spend(&client(n), 5).unwrap();
+        }
+        {
+            let mut buckets = limiter.buckets.lock();
+            let past = Instant::now() - SWEEP_INTERVAL;
+            buckets.swept = past;
+            // Clients 0..5 had enough time to refill their buckets
+            for (ip, bucket) in buckets.clients.iter_mut() {
+                if *ip < client(5).0.unwrap() {
+                    bucket.updated = past;
+                }
+            }
+        }
+        limiter.spend(&client(10), 5).unwrap();
+        let buckets = limiter.buckets.lock();
+        assert_eq!(buckets.clients.len(), 6);
+        assert!((5..11).all(|n| buckets.clients.contains_key(&client(n).0.unwrap())));
+    }
+}
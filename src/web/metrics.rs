@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+use parking_lot::Mutex;
+use rocket::{Request, Response, Data, State};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::response::content::Plain;
+use pikadots::search::Access;
+use super::Shared;
+
+/// Upper bounds of buckets, in seconds
+const BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Default)]
+struct Histogram {
+    /// Not cumulative, last one is +Inf
+    buckets: [u64; BUCKETS.len() + 1],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, time: Duration) {
+        let secs = time.as_secs_f64();
+        let idx = BUCKETS.iter().position(|x| secs <= *x).unwrap_or(BUCKETS.len());
+        self.buckets[idx] += 1;
+        self.count += 1;
+        self.sum += secs;
+    }
+
+    fn write(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        let mut total = 0;
+        for (i, cnt) in self.buckets.iter().enumerate() {
+            total += cnt;
+            let le = BUCKETS.get(i).map(|x| x.to_string()).unwrap_or_else(|| "+Inf".to_string());
+            writeln!(out, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, sep, le, total).unwrap();
+        }
+        let labels = if labels.is_empty() { String::new() } else { format!("{{{}}}", labels) };
+        writeln!(out, "{}_sum{} {}", name, labels, self.sum).unwrap();
+        writeln!(out, "{}_count{} {}", name, labels, self.count).unwrap();
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Keyed by route name and status
+    requests: HashMap<(&'static str, u16), u64>,
+    latency: HashMap<&'static str, Histogram>,
+    access: HashMap<Access, u64>,
+    render: Histogram,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    inner: Mutex<Inner>,
+}
+
+impl Metrics {
+    fn request(&self, route: &'static str, status: u16, time: Duration) {
+        let mut inner = self.inner.lock();
+        *inner.requests.entry((route, status)).or_insert(0) += 1;
+        inner.latency.entry(route).or_default().observe(time);
+    }
+
+    /// Counts how selectors were looked up
+    pub fn plan(&self, plan: &pikadots::search::Plan) {
+        let mut inner = self.inner.lock();
+        for (_, access) in &plan.steps {
+            *inner.access.entry(*access).or_insert(0) += 1;
+        }
+    }
+
+    pub fn render(&self, time: Duration) {
+        self.inner.lock().render.observe(time);
+    }
+}
+
+/// Started time of request
+struct Start(Instant);
+
+/// Measures every request, needs `Shared` to be managed
+pub struct RequestTimer;
+
+impl Fairing for RequestTimer {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request metrics",
+            kind: Kind::Request | Kind::Response
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        request.local_cache(|| Start(Instant::now()));
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let time = request.local_cache(|| Start(Instant::now())).0.elapsed();
+        let route = request.route().and_then(|x| x.name).unwrap_or("unknown");
+        if let Some(state) = request.guard::<State<Shared>>().succeeded() {
+            state.metrics.request(route, response.status().code, time);
+        }
+    }
+}
+
+fn escape_label(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[get("/metrics")]
+pub fn metrics(state: State<Shared>) -> Plain<String> {
+    let mut out = String::new();
+    {
+        let inner = state.metrics.inner.lock();
+
+        out.push_str("# HELP pikadots_requests_total Handled requests\n");
+        out.push_str("# TYPE pikadots_requests_total counter\n");
+        for ((route, status), cnt) in &inner.requests {
+            writeln!(out, "pikadots_requests_total{{route=\"{}\",status=\"{}\"}} {}", escape_label(route), status, cnt).unwrap();
+        }
+
+        out.push_str("# HELP pikadots_request_duration_seconds Time spent handling requests\n");
+        out.push_str("# TYPE pikadots_request_duration_seconds histogram\n");
+        for (route, hist) in &inner.latency {
+            hist.write(&mut out, "pikadots_request_duration_seconds", &format!("route=\"{}\"", escape_label(route)));
+        }
+
+        out.push_str("# HELP pikadots_search_selectors_total Selectors searched, by the way they were looked up\n");
+        out.push_str("# TYPE pikadots_search_selectors_total counter\n");
+        for (access, cnt) in &inner.access {
+            writeln!(out, "pikadots_search_selectors_total{{access=\"{:?}\"}} {}", access, cnt).unwrap();
+        }
+
+        out.push_str("# HELP pikadots_render_duration_seconds Time spent drawing and encoding images\n");
+        out.push_str("# TYPE pikadots_render_duration_seconds histogram\n");
+        inner.render.write(&mut out, "pikadots_render_duration_seconds", "");
+    }
+
+    let images = state.images.stats();
+    let served = state.served();
+    let cache = &served.data.cache;
+    let gauges: &[(&str, &str, &str, u64)] = &[
+        ("pikadots_data_read_bytes_total", "counter", "Bytes read from data files", pikadots::shared::total_read()),
+        ("pikadots_image_cache_hits_total", "counter", "Rendered images served from cache", images.hits as u64),
+        ("pikadots_image_cache_misses_total", "counter", "Rendered images not found in cache", images.misses as u64),
+        ("pikadots_image_cache_items", "gauge", "Images in cache", images.items as u64),
+        ("pikadots_image_cache_bytes", "gauge", "Size of images in cache", images.bytes as u64),
+        ("pikadots_cached_users", "gauge", "Users kept in memory", cache.cached.len() as u64),
+        ("pikadots_name_map_items", "gauge", "Items in names map", cache.names.len() as u64),
+        ("pikadots_id_map_items", "gauge", "Items in ids map", cache.ids.len() as u64),
+        ("pikadots_reloads_total", "counter", "Successful data reloads", state.reload.generation() as u64),
+    ];
+    for (name, kind, help, value) in gauges {
+        writeln!(out, "# HELP {} {}\n# TYPE {} {}\n{} {}", name, help, name, kind, name, value).unwrap();
+    }
+
+    out.push_str("# HELP pikadots_lock_contended_total Times a lock was already taken by another thread\n");
+    out.push_str("# TYPE pikadots_lock_contended_total counter\n");
+    writeln!(out, "pikadots_lock_contended_total{{lock=\"images\"}} {}", images.contended).unwrap();
+    writeln!(out, "pikadots_lock_contended_total{{lock=\"snapshot\"}} {}", state.reload.contended()).unwrap();
+    Plain(out)
+}
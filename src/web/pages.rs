@@ -0,0 +1,262 @@
+use rocket::State;
+use rocket::request::LenientForm;
+use rocket::response::content::Html;
+use rocket::http::uri::Uri;
+use pikadots::data::UserInfo;
+use pikadots::stats::{UserStats, format_seconds};
+use super::{WebState, Shared, Error, Client, find_user, parse_date};
+
+/// Everything interpolated into html must go through this
+pub fn escape(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => res.push_str("&amp;"),
+            '<' => res.push_str("&lt;"),
+            '>' => res.push_str("&gt;"),
+            '"' => res.push_str("&quot;"),
+            '\'' => res.push_str("&#39;"),
+            c => res.push(c)
+        }
+    }
+    res
+}
+
+/// Percent-encoded and then html-escaped, ready for `href` and `src` attributes
+fn url(s: &str) -> String {
+    escape(&Uri::percent_encode(s))
+}
+
+pub fn page(title: &str, body: &str) -> String {
+    format!(r#"<!DOCTYPE html>
+    <html>
+    <head>
+        <meta charset="utf-8">
+        <title>{title}</title>
+    </head><body>
+    {body}
+    </body></html>"#, title=escape(title), body=body)
+}
+
+fn profile_link(template: &str, user: &UserInfo) -> String {
+    template
+        .replace("{id}", &user.pikabu_id.to_string())
+        .replace("{name}", &Uri::percent_encode(&user.name))
+}
+
+pub fn users_table(state: &WebState, users: &[UserInfo]) -> String {
+    let mut res = r#"<table>
+        <thead>
+            <tr>
+                <th>Name</th>
+                <th>Id</th>
+                <th>Sk</th>
+                <th>Comment count</th>
+                <th>Other events</th>
+                <th>Rename history</th>
+            </tr>
+        </thead>
+        <tbody>
+    "#.to_string();
+    for u in users {
+        let history = u.aliases.iter()
+            .map(|x| format!(
+                "{} ({} &mdash; {})",
+                escape(&x.name), x.first_seen.format("%Y-%m-%d"), x.last_seen.format("%Y-%m-%d")
+            ))
+            .collect::<Vec<_>>()
+            .join("<br>");
+        let events = u.streams.iter()
+            .filter(|x| !x.events.is_empty())
+            .map(|x| format!("{}: {}", escape(&x.kind), x.events.len()))
+            .collect::<Vec<_>>()
+            .join("<br>");
+        let name = match &state.profile_link {
+            Some(x) => format!(r#"<a href="{}">{}</a>"#, escape(&profile_link(x, u)), escape(&u.name)),
+            None => escape(&u.name)
+        };
+        res.push_str(&format!(r#"
+            <tr>
+                <td>{name}</td>
+                <td>{pik}</td>
+                <td>{sk}</td>
+                <td align="right">{cnt}</td>
+                <td>{events}</td>
+                <td>{history}</td>
+            </tr>"#,
+            pik=u.pikabu_id, name=name, cnt=u.comments.len(),
+            sk=u.seek.map(|x| x.to_string()).unwrap_or_default(),
+            events=events, history=history
+        ))
+    }
+    res.push_str("</tbody></table>");
+    res
+}
+
+/// Values of the search form, echoed back on the user page
+#[derive(Default)]
+struct Form {
+    q: String,
+    tz: i8,
+    palette: String,
+    from: String,
+    to: String,
+    kind: String,
+}
+
+fn search_form(base: &str, form: &Form) -> String {
+    let mut palettes = String::new();
+    for x in &["", "normal", "comments", "posts"] {
+        palettes.push_str(&format!(
+            r#"<option value="{v}"{sel}>{name}</option>"#,
+            v=x, sel=if *x == form.palette { " selected" } else { "" },
+            name=if x.is_empty() { "default" } else { *x }
+        ));
+    }
+    format!(r#"
+    <form action="{base}/user.html" method="get">
+        <input name="q" list="names" value="{q}" placeholder="name, id:123, gl:foo*, re:^foo" autocomplete="off" required>
+        <datalist id="names"></datalist>
+        <label>UTC offset <input name="tz" type="number" min="-12" max="14" value="{tz}"></label>
+        <label>Palette <select name="palette">{palettes}</select></label>
+        <label>From <input name="from" type="date" value="{from}"></label>
+        <label>To <input name="to" type="date" value="{to}"></label>
+        <label>Kinds <input name="kind" value="{kind}" placeholder="comments,posts"></label>
+        <button type="submit">Show</button>
+    </form>
+    <script>
+        // Names are completed only for the last selector in the list
+        const input = document.querySelector('input[name=q]');
+        const list = document.getElementById('names');
+        let timer = null;
+        input.addEventListener('input', () => {{
+            clearTimeout(timer);
+            timer = setTimeout(async () => {{
+                const parts = input.value.split(',');
+                const prefix = parts.pop();
+                if (prefix.length < 2 || prefix.includes(':')) return;
+                const resp = await fetch('{base}/api/names?prefix=' + encodeURIComponent(prefix));
+                if (!resp.ok) return;
+                const head = parts.map(x => x + ',').join('');
+                list.replaceChildren(...(await resp.json()).map(name => {{
+                    const opt = document.createElement('option');
+                    opt.value = head + name;
+                    return opt;
+                }}));
+            }}, 200);
+        }});
+    </script>"#,
+        base=escape(base), q=escape(&form.q), tz=form.tz, palettes=palettes,
+        from=escape(&form.from), to=escape(&form.to), kind=escape(&form.kind)
+    )
+}
+
+#[get("/")]
+pub fn index(state: State<Shared>) -> Html<String> {
+    let body = format!(
+        "<h1>PikaDots</h1>{}<p>Several selectors separated by comma are drawn as one user.</p>",
+        search_form(&state.base, &Form::default())
+    );
+    Html(page("PikaDots", &body))
+}
+
+/// Query sent by the search form
+#[derive(FromForm)]
+pub struct UserQuery {
+    q: String,
+    tz: Option<i8>,
+    palette: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    kind: Option<String>,
+}
+
+#[get("/user.html?<query..>")]
+pub fn user(state: State<Shared>, client: Client, query: LenientForm<UserQuery>) -> Result<Html<String>, Error> {
+    let query = query.into_inner();
+    // Validate before putting into links
+    parse_date(query.from.clone())?;
+    parse_date(query.to.clone())?;
+    let form = Form {
+        tz: query.tz.unwrap_or(0),
+        palette: query.palette.unwrap_or_default(),
+        // Blank dates are not put into links
+        from: query.from.map_or_else(String::new, |x| x.trim().to_string()),
+        to: query.to.map_or_else(String::new, |x| x.trim().to_string()),
+        kind: query.kind.unwrap_or_default(),
+        q: query.q,
+    };
+    if !form.palette.is_empty() {
+        form.palette.parse::<pikadots::draw::Palette>()
+            .map_err(|e| Error::InvalidRequest(format!("{}", e)))?;
+    }
+    if !form.kind.is_empty() {
+        pikadots::data::parse_kinds(&form.kind)
+            .map_err(|e| Error::InvalidRequest(format!("{}", e)))?;
+    }
+    let users = find_user(&state, &client, form.q.clone())?;
+
+    let mut params = format!("tz={}", form.tz);
+    for (k, v) in &[("palette", &form.palette), ("from", &form.from), ("to", &form.to), ("kind", &form.kind)] {
+        if !v.is_empty() {
+            params.push_str(&format!("&{}={}", k, Uri::percent_encode(v)));
+        }
+    }
+    let body = format!(
+        r#"<p><a href="{base}/">PikaDots</a></p>{form}
+        <p><img src="{base}/{q}/i.png?{params}" alt="Activity of {title}"></p>
+        <p><img src="{base}/{q}/weekhour.png?{params}" alt="Activity of {title} by weekday and hour">
+        <img src="{base}/{q}/hourly.png?{params}" alt="Activity of {title} by hour"></p>
+        <p><a href="{base}/{q}/stats.html?tz={tz}">Statistics</a> | <a href="{base}/{q}/similar.html">Similar users</a></p>
+        {table}"#,
+        base=escape(&state.base), form=search_form(&state.base, &form), q=url(&form.q), params=escape(&params), tz=form.tz,
+        title=escape(&form.q), table=users_table(&state, &users)
+    );
+    Ok(Html(page(&form.q, &body)))
+}
+
+/// Horizontal bars, longest one is 100%
+fn histogram<L: IntoIterator<Item=String>>(labels: L, counts: &[usize]) -> String {
+    let max = counts.iter().cloned().max().unwrap_or(0).max(1);
+    let mut res = "<table>".to_string();
+    for (label, cnt) in labels.into_iter().zip(counts) {
+        res.push_str(&format!(
+            r#"<tr><td>{}</td><td align="right">{}</td><td><div style="background: #3cb371; height: 1em; width: {}px"></div></td></tr>"#,
+            label, cnt, cnt * 300 / max
+        ));
+    }
+    res.push_str("</table>");
+    res
+}
+
+#[get("/<query>/stats.html?<tz>")]
+pub fn stats(state: State<Shared>, client: Client, query: String, tz: Option<i8>) -> Result<Html<String>, Error> {
+    let users = find_user(&state, &client, query.clone())?;
+    let points = pikadots::join_sorted(users.into_iter().map(|x| x.comments));
+    let stats = UserStats::new(&points, tz.unwrap_or(0));
+
+    let or_none = |x: Option<String>| x.unwrap_or_else(|| "&mdash;".to_string());
+    let rows = [
+        ("Comments", stats.comments.to_string()),
+        ("First comment", or_none(stats.first.map(|x| x.to_string()))),
+        ("Last comment", or_none(stats.last.map(|x| x.to_string()))),
+        ("Active days", stats.active_days.to_string()),
+        ("Longest streak", or_none(stats.longest_streak.map(|x| format!("{} days, {} &mdash; {}", x.days, x.start, x.end)))),
+        ("Longest gap", or_none(stats.longest_gap.map(|x| format!("{}, {} &mdash; {}", format_seconds(x.seconds), x.start, x.end)))),
+        ("Median interval", or_none(stats.median_interval.map(format_seconds))),
+        ("Peak minute", format!("{} comments, {} times", stats.peak_minute, stats.peak_minutes)),
+    ];
+    let mut body = format!(
+        r#"<p><a href="{base}/">PikaDots</a> | <a href="{base}/user.html?q={q}&amp;tz={tz}">{title}</a></p><table>"#,
+        base=escape(&state.base), q=url(&query), tz=tz.unwrap_or(0), title=escape(&query)
+    );
+    for (name, value) in rows.iter() {
+        body.push_str(&format!("<tr><th align=\"left\">{}</th><td>{}</td></tr>", name, value));
+    }
+    body.push_str("</table><h2>Weekdays</h2>");
+    let weekdays = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"].iter().map(|x| x.to_string());
+    body.push_str(&histogram(weekdays, &stats.weekdays));
+    body.push_str("<h2>Hours</h2>");
+    body.push_str(&histogram((0..24).map(|x| format!("{:02}:00", x)), &stats.hours));
+    Ok(Html(page(&query, &body)))
+}
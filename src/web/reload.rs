@@ -0,0 +1,199 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+use parking_lot::{Mutex, RwLock};
+use rocket::State;
+use rocket::request::{self, FromRequest, Request};
+use rocket::Outcome;
+use pikadots::Res;
+use super::{Data, Shared, Error};
+
+/// Opens data and index files from scratch. Returns data and whether its cache can be used
+pub type Loader = Box<dyn Fn() -> Res<(Data, bool)> + Send + Sync>;
+
+/// Everything built from data files. Requests hold it until they finish, so swapping it is safe
+pub struct Served {
+    pub data: Data,
+    pub cache: bool,
+    /// Sorted lowercase names for autocomplete
+    pub names: Vec<String>,
+}
+
+impl Served {
+    pub fn new((data, cache): (Data, bool)) -> Self {
+        let mut names: Vec<String> = data.cache.names.keys().cloned().collect();
+        names.sort();
+        Served { data, cache, names }
+    }
+}
+
+pub struct Reload {
+    loader: Loader,
+    current: RwLock<Arc<Served>>,
+    token: Option<String>,
+    running: AtomicBool,
+    generation: AtomicUsize,
+    last_error: Mutex<Option<String>>,
+    contended: AtomicUsize,
+}
+
+impl Reload {
+    pub fn new(loader: Loader, token: Option<String>) -> Res<Self> {
+        let served = Served::new(loader()?);
+        Ok(Reload {
+            loader,
+            current: RwLock::new(Arc::new(served)),
+            token,
+            running: AtomicBool::new(false),
+            generation: AtomicUsize::new(0),
+            last_error: Mutex::new(None),
+            contended: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn current(&self) -> Arc<Served> {
+        match self.current.try_read() {
+            Some(x) => x.clone(),
+            None => {
+                // Only happens while new data is swapped in
+                self.contended.fetch_add(1, Ordering::Relaxed);
+                self.current.read().clone()
+            }
+        }
+    }
+
+    /// Times current data was locked by reload
+    pub fn contended(&self) -> usize {
+        self.contended.load(Ordering::Relaxed)
+    }
+
+    pub fn generation(&self) -> usize {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.lock().clone()
+    }
+}
+
+/// Marks reload as running. Returns false if another reload is already running
+fn begin(shared: &Shared) -> bool {
+    !shared.reload.running.swap(true, Ordering::SeqCst)
+}
+
+/// Loads new data after successful `begin`. Returns whether data was replaced
+fn run(shared: &Shared) -> bool {
+    eprintln!("Reloading data...");
+    let reload = &shared.reload;
+    let res = match (reload.loader)() {
+        Ok(x) => {
+            let served = Arc::new(Served::new(x));
+            *reload.current.write() = served;
+            // Generation is changed after data, so requests seeing the new one use new data
+            reload.generation.fetch_add(1, Ordering::Release);
+            // Old images are never hit again, so they only take memory
+            shared.images.clear();
+            *reload.last_error.lock() = None;
+            eprintln!("Data reloaded");
+            true
+        },
+        Err(e) => {
+            eprintln!("Reload failed: {:?}", e);
+            *reload.last_error.lock() = Some(format!("{:?}", e));
+            false
+        }
+    };
+    reload.running.store(false, Ordering::SeqCst);
+    res
+}
+
+/// Loads new data in a separate thread. Returns false if another reload is already running
+fn start(shared: &Shared) -> bool {
+    if !begin(shared) {
+        return false;
+    }
+    let shared = shared.clone();
+    std::thread::spawn(move || run(&shared));
+    true
+}
+
+/// Polls modification time of files and reloads data when any of them changes
+pub fn watch(shared: Shared, files: Vec<PathBuf>, interval: Duration) {
+    let modified = move || -> Vec<Option<SystemTime>> {
+        files.iter()
+            .map(|x| std::fs::metadata(x).and_then(|x| x.modified()).ok())
+            .collect()
+    };
+    std::thread::spawn(move || {
+        let mut last = modified();
+        loop {
+            std::thread::sleep(interval);
+            let now = modified();
+            if now != last {
+                // File may be still written, so wait until it stops changing
+                std::thread::sleep(interval);
+                if modified() != now {
+                    continue;
+                }
+                // Failed reload is retried on the next poll
+                if begin(&shared) && run(&shared) {
+                    last = now;
+                }
+            }
+        }
+    });
+}
+
+/// Compares tokens in time depending only on their length, so it doesn't tell how much of a guess is right
+fn same_token(expected: &str, got: &str) -> bool {
+    expected.len() == got.len()
+        && expected.bytes().zip(got.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Token from `Authorization: Bearer <token>` header
+pub struct Token(Option<String>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for Token {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        let token = request.headers().get_one("Authorization")
+            .and_then(|x| {
+                let mut parts = x.splitn(2, ' ');
+                match (parts.next(), parts.next()) {
+                    (Some("Bearer"), Some(token)) => Some(token.trim().to_string()),
+                    _ => None
+                }
+            });
+        Outcome::Success(Token(token))
+    }
+}
+
+#[post("/reload")]
+pub fn reload(state: State<Shared>, token: Token) -> Result<String, Error> {
+    match (&state.reload.token, token.0) {
+        (None, _) => return Err(Error::NotFound("Reload is disabled")),
+        (Some(expected), Some(got)) if same_token(expected, &got) => {},
+        _ => return Err(Error::Forbidden("Invalid reload token"))
+    }
+    if start(&state) {
+        Ok("Reload started".to_string())
+    } else {
+        Ok("Reload is already running".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_tokens() {
+        assert!(same_token("secret", "secret"));
+        assert!(!same_token("secret", "secreT"));
+        assert!(!same_token("secret", "secret1"));
+        assert!(!same_token("secret", ""));
+        assert!(same_token("", ""));
+    }
+}